@@ -0,0 +1,272 @@
+// src/browse.rs
+
+use std::{cmp::Ordering, collections::HashMap, path::PathBuf};
+
+use crate::{library::{Library, Track}, tags::Tags};
+
+// ファイルブラウザの表示のしかた。フォルダ以外はライブラリのタグから階層を作る
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum View {
+    #[default]
+    Folders,
+    Artists,
+    Genres,
+    Years,
+}
+
+impl View {
+    pub fn next(self) -> Self {
+        match self {
+            View::Folders => View::Artists,
+            View::Artists => View::Genres,
+            View::Genres => View::Years,
+            View::Years => View::Folders,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            View::Folders => "Folders",
+            View::Artists => "Artists",
+            View::Genres => "Genres",
+            View::Years => "Years",
+        }
+    }
+
+    // 上の階層から順に何でまとめるか。最後の階層の下に曲が並ぶ
+    fn levels(self) -> &'static [Field] {
+        match self {
+            View::Folders => &[],
+            View::Artists => &[Field::Artist, Field::Album],
+            View::Genres => &[Field::Genre, Field::Album],
+            View::Years => &[Field::Year, Field::Album],
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    Artist,
+    Album,
+    Genre,
+    Year,
+}
+
+impl Field {
+    fn value(self, tags: &Tags) -> Option<String> {
+        match self {
+            Field::Artist => tags.artist.clone(),
+            Field::Album => tags.album.clone(),
+            Field::Genre => tags.genre.clone(),
+            Field::Year => tags.year.map(|y| y.to_string()),
+        }
+    }
+}
+
+// タグ表示でのアーティストやアルバムなどのまとまり。value が None ならそのタグが無い曲
+#[derive(Clone, PartialEq)]
+pub struct Group {
+    field: Field,
+    value: Option<String>,
+}
+
+impl Group {
+    pub fn label(&self) -> &str {
+        match (&self.value, self.field) {
+            (Some(value), _) => value,
+            (None, Field::Artist) => "Unknown Artist",
+            (None, Field::Album) => "Unknown Album",
+            (None, Field::Genre) => "Unknown Genre",
+            (None, Field::Year) => "Unknown Year",
+        }
+    }
+
+    fn contains(&self, track: &Track) -> bool {
+        self.field.value(&track.tags) == self.value
+    }
+}
+
+// ファイルブラウザの1項目
+#[derive(Clone, PartialEq)]
+pub enum Entry {
+    // フォルダ表示ではファイルかディレクトリ、タグ表示では曲
+    Path(PathBuf),
+    Group(Group),
+}
+
+impl Entry {
+    pub fn path(&self) -> Option<&PathBuf> {
+        match self {
+            Entry::Path(path) => Some(path),
+            Entry::Group(_) => None,
+        }
+    }
+}
+
+// groups の中に入ったときの一覧。まとまりの階層ならその下のまとまりを、最後の階層なら曲を並べる
+pub fn list(library: &Library, view: View, groups: &[Group]) -> Vec<Entry> {
+    let mut tracks: Vec<&Track> = library
+        .tracks()
+        .filter(|t| groups.iter().all(|g| g.contains(t)))
+        .collect();
+    let Some(&field) = view.levels().get(groups.len()) else {
+        // アルバムの中はトラック番号順。番号の無い曲は後ろにパス順で並べる
+        tracks.sort_by(|a, b| {
+            a.tags.track.is_none()
+                .cmp(&b.tags.track.is_none())
+                .then(a.tags.track.cmp(&b.tags.track))
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        return tracks.into_iter().map(|t| Entry::Path(t.path.clone())).collect();
+    };
+
+    // まとまりごとに、その中でいちばん古い年を覚えておく (アルバムを発売順に並べるため)
+    let mut years: HashMap<Option<String>, Option<u32>> = HashMap::new();
+    for track in tracks {
+        let year = years.entry(field.value(&track.tags)).or_insert(track.tags.year);
+        *year = match (*year, track.tags.year) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+    let mut values: Vec<(Option<String>, Option<u32>)> = years.into_iter().collect();
+    values.sort_by(|(a, a_year), (b, b_year)| {
+        // タグの無いまとまりは最後に置く
+        a.is_none().cmp(&b.is_none()).then_with(|| match field {
+            Field::Album => a_year
+                .is_none()
+                .cmp(&b_year.is_none())
+                .then(a_year.cmp(b_year))
+                .then_with(|| compare_names(a, b)),
+            Field::Year => a.as_ref().and_then(|y| y.parse::<u32>().ok())
+                .cmp(&b.as_ref().and_then(|y| y.parse::<u32>().ok())),
+            Field::Artist | Field::Genre => compare_names(a, b),
+        })
+    });
+    values.into_iter().map(|(value, _)| Entry::Group(Group { field, value })).collect()
+}
+
+// groups の中にあるすべての曲を、一覧で表示する順に並べる
+pub fn tracks(library: &Library, view: View, groups: &[Group]) -> Vec<PathBuf> {
+    list(library, view, groups)
+        .into_iter()
+        .flat_map(|entry| match entry {
+            Entry::Path(path) => vec![path],
+            Entry::Group(group) => {
+                let mut inner = groups.to_vec();
+                inner.push(group);
+                tracks(library, view, &inner)
+            }
+        })
+        .collect()
+}
+
+// 大文字小文字を区別せずに比べる
+fn compare_names(a: &Option<String>, b: &Option<String>) -> Ordering {
+    let lower = |name: &Option<String>| name.as_deref().map(str::to_lowercase);
+    lower(a).cmp(&lower(b)).then_with(|| a.cmp(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use super::*;
+
+    fn track(path: &str, artist: Option<&str>, album: Option<&str>, number: Option<u32>, year: Option<u32>) -> Track {
+        let tags = Tags {
+            artist: artist.map(str::to_string),
+            album: album.map(str::to_string),
+            track: number,
+            year,
+            ..Tags::default()
+        };
+        Track { path: PathBuf::from(path), modified: Duration::ZERO, size: 0, tags }
+    }
+
+    fn labels(entries: &[Entry]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| match entry {
+                Entry::Group(group) => group.label(),
+                Entry::Path(path) => path.to_str().unwrap(),
+            })
+            .collect()
+    }
+
+    fn group(entries: &[Entry], label: &str) -> Group {
+        entries
+            .iter()
+            .find_map(|entry| match entry {
+                Entry::Group(group) if group.label() == label => Some(group.clone()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn library() -> Library {
+        Library::from_tracks([
+            track("/m/b/late/2.mp3", Some("beta"), Some("Late"), Some(2), Some(2010)),
+            track("/m/b/late/1.mp3", Some("beta"), Some("Late"), Some(1), Some(2010)),
+            track("/m/b/late/bonus.mp3", Some("beta"), Some("Late"), None, None),
+            track("/m/b/late/10.mp3", Some("beta"), Some("Late"), Some(10), Some(2010)),
+            track("/m/b/early/1.mp3", Some("beta"), Some("Early"), Some(1), Some(1999)),
+            // 再発盤の曲が混じっていても、いちばん古い年で並べる
+            track("/m/b/zed/1.mp3", Some("beta"), Some("Zed"), Some(1), Some(2020)),
+            track("/m/b/zed/2.mp3", Some("beta"), Some("Zed"), Some(2), Some(1995)),
+            track("/m/b/undated.mp3", Some("beta"), Some("Undated"), None, None),
+            track("/m/b/loose.mp3", Some("beta"), None, None, Some(1980)),
+            track("/m/a/1.mp3", Some("Alpha"), Some("One"), Some(1), Some(800)),
+            track("/m/unknown.mp3", None, None, None, Some(10_000)),
+        ])
+    }
+
+    #[test]
+    fn artists_are_sorted_by_name_ignoring_case_with_unknown_last() {
+        let entries = list(&library(), View::Artists, &[]);
+        assert_eq!(labels(&entries), ["Alpha", "beta", "Unknown Artist"]);
+    }
+
+    #[test]
+    fn albums_are_sorted_by_earliest_year_with_unknown_last() {
+        let library = library();
+        let beta = group(&list(&library, View::Artists, &[]), "beta");
+        let entries = list(&library, View::Artists, &[beta]);
+        assert_eq!(labels(&entries), ["Zed", "Early", "Late", "Undated", "Unknown Album"]);
+    }
+
+    #[test]
+    fn tracks_are_sorted_by_number_then_untagged_by_path() {
+        let library = library();
+        let beta = group(&list(&library, View::Artists, &[]), "beta");
+        let late = group(&list(&library, View::Artists, std::slice::from_ref(&beta)), "Late");
+        let entries = list(&library, View::Artists, &[beta, late]);
+        assert_eq!(labels(&entries), ["/m/b/late/1.mp3", "/m/b/late/2.mp3", "/m/b/late/10.mp3", "/m/b/late/bonus.mp3"]);
+    }
+
+    #[test]
+    fn years_are_sorted_numerically_with_unknown_last() {
+        let entries = list(&library(), View::Years, &[]);
+        assert_eq!(labels(&entries), ["800", "1980", "1995", "1999", "2010", "2020", "10000", "Unknown Year"]);
+    }
+
+    #[test]
+    fn tracks_follow_the_listed_order_through_every_level() {
+        let library = library();
+        let beta = group(&list(&library, View::Artists, &[]), "beta");
+        let paths = tracks(&library, View::Artists, &[beta]);
+        let expected = [
+            "/m/b/zed/1.mp3",
+            "/m/b/zed/2.mp3",
+            "/m/b/early/1.mp3",
+            "/m/b/late/1.mp3",
+            "/m/b/late/2.mp3",
+            "/m/b/late/10.mp3",
+            "/m/b/late/bonus.mp3",
+            "/m/b/undated.mp3",
+            "/m/b/loose.mp3",
+        ];
+        assert_eq!(paths, expected.map(Path::new));
+        assert_eq!(tracks(&library, View::Folders, &[]).len(), library.tracks().count());
+    }
+}
@@ -0,0 +1,181 @@
+// src/cli.rs
+
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{output::OutputKind, queue::RepeatMode};
+
+pub const USAGE: &str = "\
+Usage: music_cli [OPTIONS] [PATH]
+
+Arguments:
+  [PATH]  Directory to browse, or a file or playlist to play
+
+Options:
+      --shuffle           Start with shuffle on
+      --repeat[=MODE]     Repeat mode: off, one or all (default: all)
+      --volume <PERCENT>  Initial volume, 0-100
+      --no-ui             Play without the terminal UI
+      --output <OUTPUT>   Audio output: device, null or wav:FILE
+  -h, --help              Print help
+  -V, --version           Print version
+";
+
+// 起動時の指定。None の項目は保存してある値や既定値を使う
+pub struct Args {
+    pub path: Option<PathBuf>,
+    pub shuffle: bool,
+    pub repeat: Option<RepeatMode>,
+    // 0.0〜1.0
+    pub volume: Option<f32>,
+    pub no_ui: bool,
+    pub output: OutputKind,
+}
+
+pub enum Command {
+    Run(Args),
+    Help,
+    Version,
+}
+
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command> {
+    let mut parsed = Args {
+        path: None,
+        shuffle: false,
+        repeat: None,
+        volume: None,
+        no_ui: false,
+        output: OutputKind::Device,
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // "--name=value" と "--name value" のどちらでも値を受け取る
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) if arg.starts_with("--") => (name.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = |name: &str| {
+            inline.clone().or_else(|| args.next()).ok_or_else(|| anyhow!("{} needs a value", name))
+        };
+        match name.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "--shuffle" => parsed.shuffle = true,
+            "--no-ui" => parsed.no_ui = true,
+            // 値を省略したら全曲リピート。次の引数はパスかもしれないので値としては取らない
+            "--repeat" => parsed.repeat = Some(parse_repeat(inline.as_deref().unwrap_or("all"))?),
+            "--volume" => {
+                let volume = value("--volume")?;
+                let percent: f32 = volume
+                    .parse()
+                    .map_err(|_| anyhow!("invalid volume '{}' (expected 0-100)", volume))?;
+                if !(0.0..=100.0).contains(&percent) {
+                    bail!("volume {} is out of range (expected 0-100)", volume);
+                }
+                parsed.volume = Some(percent / 100.0);
+            }
+            "--output" => parsed.output = OutputKind::parse(&value("--output")?)?,
+            _ if name.starts_with('-') && name != "-" => bail!("unknown option '{}'\n\n{}", name, USAGE),
+            _ if parsed.path.is_none() => parsed.path = Some(PathBuf::from(arg)),
+            _ => bail!("unexpected argument '{}'\n\n{}", arg, USAGE),
+        }
+    }
+    Ok(Command::Run(parsed))
+}
+
+fn parse_repeat(mode: &str) -> Result<RepeatMode> {
+    match mode {
+        "off" => Ok(RepeatMode::Off),
+        "one" => Ok(RepeatMode::One),
+        "all" => Ok(RepeatMode::All),
+        _ => bail!("invalid repeat mode '{}' (expected off, one or all)", mode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command> {
+        parse(args.iter().map(|a| a.to_string()))
+    }
+
+    fn run(args: &[&str]) -> Args {
+        match parse_args(args) {
+            Ok(Command::Run(parsed)) => parsed,
+            Ok(_) => panic!("expected Run for {:?}", args),
+            Err(e) => panic!("unexpected error for {:?}: {}", args, e),
+        }
+    }
+
+    fn error(args: &[&str]) -> String {
+        match parse_args(args) {
+            Ok(_) => panic!("expected an error for {:?}", args),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn no_arguments_use_defaults() {
+        let parsed = run(&[]);
+        assert!(parsed.path.is_none());
+        assert!(!parsed.shuffle);
+        assert!(parsed.repeat.is_none());
+        assert!(parsed.volume.is_none());
+        assert!(!parsed.no_ui);
+        assert!(matches!(parsed.output, OutputKind::Device));
+    }
+
+    #[test]
+    fn reads_options_and_path() {
+        let parsed = run(&["--shuffle", "--volume", "25", "--no-ui", "--output=wav:out.wav", "~/Music"]);
+        assert_eq!(parsed.path, Some(PathBuf::from("~/Music")));
+        assert!(parsed.shuffle);
+        assert_eq!(parsed.volume, Some(0.25));
+        assert!(parsed.no_ui);
+        assert!(matches!(parsed.output, OutputKind::Wav(path) if path.as_os_str() == "out.wav"));
+    }
+
+    #[test]
+    fn accepts_values_inline_or_as_the_next_argument() {
+        assert_eq!(run(&["--volume=80"]).volume, Some(0.8));
+        assert_eq!(run(&["--volume", "80"]).volume, Some(0.8));
+        assert!(matches!(run(&["--output", "null"]).output, OutputKind::Null));
+    }
+
+    #[test]
+    fn repeat_defaults_to_all_without_taking_the_path() {
+        let parsed = run(&["--repeat", "song.mp3"]);
+        assert!(parsed.repeat == Some(RepeatMode::All));
+        assert_eq!(parsed.path, Some(PathBuf::from("song.mp3")));
+        assert!(run(&["--repeat=one"]).repeat == Some(RepeatMode::One));
+        assert!(run(&["--repeat=off"]).repeat == Some(RepeatMode::Off));
+    }
+
+    #[test]
+    fn help_and_version_stop_parsing() {
+        assert!(matches!(parse_args(&["-h", "--bogus"]), Ok(Command::Help)));
+        assert!(matches!(parse_args(&["--help"]), Ok(Command::Help)));
+        assert!(matches!(parse_args(&["--shuffle", "-V"]), Ok(Command::Version)));
+        assert!(matches!(parse_args(&["--version"]), Ok(Command::Version)));
+    }
+
+    #[test]
+    fn a_lone_dash_is_a_path() {
+        assert_eq!(run(&["-"]).path, Some(PathBuf::from("-")));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert_eq!(error(&["--volume"]), "--volume needs a value");
+        assert_eq!(error(&["--volume", "loud"]), "invalid volume 'loud' (expected 0-100)");
+        assert_eq!(error(&["--volume=101"]), "volume 101 is out of range (expected 0-100)");
+        assert_eq!(error(&["--volume=-1"]), "volume -1 is out of range (expected 0-100)");
+        assert_eq!(error(&["--repeat=twice"]), "invalid repeat mode 'twice' (expected off, one or all)");
+        assert_eq!(error(&["--output"]), "--output needs a value");
+        assert_eq!(error(&["--output", "wav:"]), "unknown output 'wav:' (expected device, null or wav:FILE)");
+        assert_eq!(error(&["--loud"]), format!("unknown option '--loud'\n\n{}", USAGE));
+        assert_eq!(error(&["a", "b"]), format!("unexpected argument 'b'\n\n{}", USAGE));
+    }
+}
@@ -0,0 +1,509 @@
+// src/config.rs
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ratatui::style::Color;
+use serde::Deserialize;
+use toml::{Spanned, Value};
+use toml_edit::DocumentMut;
+
+use crate::{
+    eq::{self, EqPreset},
+    format::AudioFormat,
+    keymap::{self, Action, Keymap},
+    queue::RepeatMode,
+};
+
+// $XDG_CONFIG_HOME/music_cli/config.toml の設定。書かれていない項目は既定値のまま
+pub struct Config {
+    pub music_dir: Option<PathBuf>,
+    pub shuffle: bool,
+    pub repeat: RepeatMode,
+    // None なら前回終了時の音量を使う
+    pub volume: Option<f32>,
+    // 起動時に前回終了時の画面と曲から続けるか。続けるときに一時停止で始めるか
+    pub restore_session: bool,
+    pub resume_paused: bool,
+    pub poll_interval: Duration,
+    // ブラウザやキューで曲として扱う形式
+    pub formats: Vec<AudioFormat>,
+    pub theme: Theme,
+    pub keymap: Keymap,
+    // [eq_presets] に保存してある利用者の EQ プリセット
+    pub eq_presets: Vec<EqPreset>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            music_dir: None,
+            shuffle: false,
+            repeat: RepeatMode::Off,
+            volume: None,
+            restore_session: true,
+            resume_paused: true,
+            poll_interval: Duration::from_millis(50),
+            formats: AudioFormat::ALL.to_vec(),
+            theme: Theme::default(),
+            keymap: Keymap::default(),
+            eq_presets: Vec::new(),
+        }
+    }
+}
+
+// 画面の色
+pub struct Theme {
+    // フォーカスのある枠や、フッターの状態表示
+    pub accent: Color,
+    pub directory: Color,
+    pub playing: Color,
+    // 再生できないファイルや、進捗バーの背景
+    pub dimmed: Color,
+    // 曲情報の項目名
+    pub label: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            accent: Color::Yellow,
+            directory: Color::Cyan,
+            playing: Color::Green,
+            dimmed: Color::DarkGray,
+            label: Color::Cyan,
+        }
+    }
+}
+
+impl Config {
+    // 設定ファイルを読む。ファイルが無ければ既定値を返す
+    pub fn load() -> Result<Self> {
+        match config_file() {
+            Some(path) if path.exists() => {
+                let text = fs::read_to_string(&path)
+                    .with_context(|| format!("cannot read {}", path.display()))?;
+                Config::parse(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))
+            }
+            _ => Ok(Config::default()),
+        }
+    }
+
+    // エラーは "line 行番号: 内容" の形で返す
+    fn parse(text: &str) -> Result<Self> {
+        let file: ConfigFile = toml::from_str(text).map_err(|e| match e.span() {
+            Some(span) => anyhow!("line {}: {}", line_of(text, span.start), e.message()),
+            None => anyhow!("{}", e.message()),
+        })?;
+        let mut config = Config::default();
+        let settings = [
+            ("music_dir", &file.music_dir),
+            ("shuffle", &file.shuffle),
+            ("repeat", &file.repeat),
+            ("volume", &file.volume),
+            ("restore_session", &file.restore_session),
+            ("resume_paused", &file.resume_paused),
+            ("poll_interval_ms", &file.poll_interval_ms),
+            ("formats", &file.formats),
+        ];
+        for (key, value) in settings {
+            if let Some(value) = value {
+                let line = line_of(text, value.span().start);
+                config.apply(key, value.get_ref()).map_err(|e| anyhow!("line {}: {}", line, e))?;
+            }
+        }
+        for (key, value) in &file.theme {
+            let line = line_of(text, key.span().start);
+            config.apply_color(key.get_ref(), value.get_ref()).map_err(|e| anyhow!("line {}: {}", line, e))?;
+        }
+        // 割り当ての衝突は、ほかの操作の既定のキーも含めて全体を見て調べる
+        let mut key_overrides = Vec::new();
+        for (key, value) in &file.keys {
+            let line = line_of(text, key.span().start);
+            key_overrides.push(parse_binding(key.get_ref(), value.get_ref()).map_err(|e| anyhow!("line {}: {}", line, e))?);
+        }
+        config.keymap = Keymap::new(&key_overrides).map_err(|e| anyhow!("[keys]: {}", e))?;
+        for (name, value) in &file.eq_presets {
+            let line = line_of(text, name.span().start);
+            let gains = parse_gains(name.get_ref(), value.get_ref()).map_err(|e| anyhow!("line {}: {}", line, e))?;
+            config.eq_presets.push(EqPreset { name: name.get_ref().clone(), gains, builtin: false });
+        }
+        Ok(config)
+    }
+
+    fn apply(&mut self, key: &str, value: &Value) -> Result<()> {
+        match key {
+            "music_dir" => self.music_dir = Some(expand_home(as_str(value, key)?)),
+            "shuffle" => self.shuffle = as_bool(value, key)?,
+            "restore_session" => self.restore_session = as_bool(value, key)?,
+            "resume_paused" => self.resume_paused = as_bool(value, key)?,
+            "repeat" => {
+                self.repeat = match as_str(value, key)? {
+                    "off" => RepeatMode::Off,
+                    "one" => RepeatMode::One,
+                    "all" => RepeatMode::All,
+                    other => bail!("repeat must be \"off\", \"one\" or \"all\", not \"{}\"", other),
+                }
+            }
+            "volume" => {
+                let volume = match value {
+                    Value::Integer(n) => *n as f64,
+                    Value::Float(n) => *n,
+                    other => bail!("volume must be a number, not {}", type_name(other)),
+                };
+                if !(0.0..=100.0).contains(&volume) {
+                    bail!("volume must be between 0 and 100, not {}", volume);
+                }
+                self.volume = Some(volume as f32 / 100.0);
+            }
+            "poll_interval_ms" => {
+                let ms = as_integer(value, key)?;
+                if !(1..=1000).contains(&ms) {
+                    bail!("poll_interval_ms must be between 1 and 1000, not {}", ms);
+                }
+                self.poll_interval = Duration::from_millis(ms as u64);
+            }
+            "formats" => {
+                let Value::Array(names) = value else { bail!("formats must be an array of strings") };
+                let mut formats = Vec::new();
+                for name in names {
+                    let name = as_str(name, key)?;
+                    let format = AudioFormat::from_name(name)
+                        .ok_or_else(|| anyhow!("unknown format \"{}\" in formats", name))?;
+                    formats.push(format);
+                }
+                self.formats = formats;
+            }
+            _ => unreachable!("setting {} is not read from ConfigFile", key),
+        }
+        Ok(())
+    }
+
+    fn apply_color(&mut self, key: &str, value: &Value) -> Result<()> {
+        let color = parse_color(as_str(value, key)?)?;
+        match key {
+            "accent" => self.theme.accent = color,
+            "directory" => self.theme.directory = color,
+            "playing" => self.theme.playing = color,
+            "dimmed" => self.theme.dimmed = color,
+            "label" => self.theme.label = color,
+            _ => bail!("unknown theme color \"{}\"", key),
+        }
+        Ok(())
+    }
+}
+
+// 設定ファイルの中身。値の型や範囲は Config::apply で調べて、行番号付きのエラーにする
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    music_dir: Option<Spanned<Value>>,
+    shuffle: Option<Spanned<Value>>,
+    repeat: Option<Spanned<Value>>,
+    volume: Option<Spanned<Value>>,
+    restore_session: Option<Spanned<Value>>,
+    resume_paused: Option<Spanned<Value>>,
+    poll_interval_ms: Option<Spanned<Value>>,
+    formats: Option<Spanned<Value>>,
+    theme: Section,
+    keys: Section,
+    eq_presets: Section,
+}
+
+// [theme] や [keys] のように、名前を自由に書ける表
+type Section = BTreeMap<Spanned<String>, Spanned<Value>>;
+
+// [keys] の1行。値はキーの並び1つか、その配列
+fn parse_binding(key: &str, value: &Value) -> Result<(Action, Vec<Vec<keymap::KeyPress>>)> {
+    let action = Action::from_name(key).ok_or_else(|| anyhow!("unknown action \"{}\" in [keys]", key))?;
+    let texts = match value {
+        // 空の配列ではその操作がどのキーでも使えなくなり、quit なら終了できなくなる
+        Value::Array(items) if items.is_empty() => bail!("empty key binding"),
+        Value::Array(items) => items.iter().map(|v| as_str(v, key)).collect::<Result<Vec<_>>>()?,
+        value => vec![as_str(value, key)?],
+    };
+    let keys = texts.into_iter().map(keymap::parse_sequence).collect::<Result<Vec<_>>>()?;
+    Ok((action, keys))
+}
+
+// [eq_presets] の1行。10バンドのゲイン (dB) の配列
+fn parse_gains(name: &str, value: &Value) -> Result<[f32; 10]> {
+    let gains: Option<Vec<f32>> = match value {
+        Value::Array(items) => items
+            .iter()
+            .map(|v| match v {
+                Value::Integer(n) => Some(*n as f32),
+                Value::Float(n) => Some(*n as f32),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    };
+    let gains: [f32; 10] = gains
+        .and_then(|g| g.try_into().ok())
+        .ok_or_else(|| anyhow!("eq preset \"{}\" must be an array of 10 numbers", name))?;
+    Ok(gains.map(|g| g.clamp(-eq::MAX_GAIN, eq::MAX_GAIN)))
+}
+
+// 利用者の EQ プリセットを config.toml の [eq_presets] に書き込む
+// ほかの設定やコメントはそのまま残す
+pub fn save_eq_presets(presets: &[EqPreset]) -> Result<()> {
+    let Some(path) = config_file() else { return Ok(()) };
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+    };
+    let text = replace_eq_presets(&text, presets).with_context(|| format!("cannot parse {}", path.display()))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, text)?;
+    Ok(())
+}
+
+fn replace_eq_presets(text: &str, presets: &[EqPreset]) -> Result<String> {
+    let mut document: DocumentMut = text.parse()?;
+    let mut table = toml_edit::Table::new();
+    for preset in presets.iter().filter(|p| !p.builtin) {
+        let gains: toml_edit::Array = preset.gains.iter().map(|&g| g as f64).collect();
+        table.insert(&preset.name, toml_edit::value(gains));
+    }
+    document.insert("eq_presets", toml_edit::Item::Table(table));
+    Ok(document.to_string())
+}
+
+// バイト位置が何行目か (1 から数える)
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "a string",
+        Value::Integer(_) => "an integer",
+        Value::Float(_) => "a float",
+        Value::Boolean(_) => "a boolean",
+        Value::Datetime(_) => "a date",
+        Value::Array(_) => "an array",
+        Value::Table(_) => "a table",
+    }
+}
+
+fn as_str<'a>(value: &'a Value, key: &str) -> Result<&'a str> {
+    match value {
+        Value::String(s) => Ok(s),
+        other => bail!("{} must be a string, not {}", key, type_name(other)),
+    }
+}
+
+fn as_integer(value: &Value, key: &str) -> Result<i64> {
+    match value {
+        Value::Integer(n) => Ok(*n),
+        other => bail!("{} must be an integer, not {}", key, type_name(other)),
+    }
+}
+
+fn as_bool(value: &Value, key: &str) -> Result<bool> {
+    match value {
+        Value::Boolean(b) => Ok(*b),
+        other => bail!("{} must be true or false, not {}", key, type_name(other)),
+    }
+}
+
+// $XDG_CONFIG_HOME/music_cli/config.toml。未設定なら ~/.config を使う
+// dirs::config_dir は Linux 以外では XDG_CONFIG_HOME を見ないので、どの OS でも自分で決める
+fn config_file() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        // XDG の仕様どおり、相対パスは無視する
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| dirs::home_dir().map(|home| home.join(".config")))?;
+    Some(dir.join("music_cli").join("config.toml"))
+}
+
+// 先頭の ~ をホームディレクトリに置き換える
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest.trim_start_matches('/')),
+        _ => Path::new(path).to_path_buf(),
+    }
+}
+
+// 色の名前か "#rrggbb"
+fn parse_color(name: &str) -> Result<Color> {
+    if let Some(hex) = name.strip_prefix('#')
+        && hex.len() == 6
+        && let Ok(rgb) = u32::from_str_radix(hex, 16)
+    {
+        return Ok(Color::Rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8));
+    }
+    Ok(match name.to_ascii_lowercase().replace(['_', '-', ' '], "").as_str() {
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" | "grey" => Color::Gray,
+        "darkgray" | "darkgrey" => Color::DarkGray,
+        "lightred" => Color::LightRed,
+        "lightgreen" => Color::LightGreen,
+        "lightyellow" => Color::LightYellow,
+        "lightblue" => Color::LightBlue,
+        "lightmagenta" => Color::LightMagenta,
+        "lightcyan" => Color::LightCyan,
+        "white" => Color::White,
+        _ => bail!("unknown color \"{}\" (use a color name or \"#rrggbb\")", name),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+    use crate::keymap::{Context, KeyPress, Resolution};
+
+    fn parse_error(text: &str) -> String {
+        match Config::parse(text) {
+            Ok(_) => panic!("expected an error for {:?}", text),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    fn resolves_to(keymap: &Keymap, c: char) -> Option<Action> {
+        let key = KeyPress::from(KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE));
+        match keymap.resolve(Context::Main, &[key]) {
+            Resolution::Action(action) => Some(action),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parse_accepts_any_valid_toml() {
+        let config = Config::parse(
+            "# comment\n\
+             music_dir = \"\"\"\n/srv/music\"\"\" # trailing\n\
+             volume = 37.5\n\
+             theme = { accent = 'red' }\n\
+             keys.quit = [\n  \"Q\", # first\n  '<C-q>',\n]\n",
+        )
+        .unwrap();
+        assert_eq!(config.music_dir, Some(PathBuf::from("/srv/music")));
+        assert_eq!(config.volume, Some(0.375));
+        assert_eq!(config.theme.accent, Color::Red);
+        assert_eq!(resolves_to(&config.keymap, 'Q'), Some(Action::Quit));
+    }
+
+    #[test]
+    fn parse_reports_toml_syntax_errors_with_line_numbers() {
+        assert!(parse_error("[keys").starts_with("line 1: "));
+        assert!(parse_error("\nname = abc").starts_with("line 2: "));
+        assert_eq!(parse_error("shuffle = true\nshuffle = false"), "line 2: duplicate key");
+        assert!(parse_error("\ncolour = 1").starts_with("line 2: unknown field `colour`"));
+        assert!(parse_error("[sound]\nvolume = 1").starts_with("line 1: unknown field `sound`"));
+    }
+
+    #[test]
+    fn parse_reads_every_setting() {
+        let config = Config::parse(
+            "music_dir = \"/srv/music\"\n\
+             shuffle = true\n\
+             repeat = \"all\"\n\
+             volume = 40\n\
+             restore_session = false\n\
+             resume_paused = false\n\
+             poll_interval_ms = 20\n\
+             formats = [\"flac\", \"mp3\"]\n\
+             [theme]\n\
+             accent = \"light-blue\"\n\
+             label = \"#102030\"\n\
+             [keys]\n\
+             quit = [\"Q\", \"<C-c>\"]\n",
+        )
+        .unwrap();
+        assert_eq!(config.music_dir, Some(PathBuf::from("/srv/music")));
+        assert!(config.shuffle);
+        assert!(config.repeat == RepeatMode::All);
+        assert_eq!(config.volume, Some(0.4));
+        assert!(!config.restore_session);
+        assert!(!config.resume_paused);
+        assert_eq!(config.poll_interval, Duration::from_millis(20));
+        assert_eq!(config.formats, [AudioFormat::Flac, AudioFormat::Mp3]);
+        assert_eq!(config.theme.accent, Color::LightBlue);
+        assert_eq!(config.theme.label, Color::Rgb(0x10, 0x20, 0x30));
+        assert_eq!(config.theme.playing, Theme::default().playing);
+        // 設定した操作は既定のキーが外れる
+        assert_eq!(resolves_to(&config.keymap, 'Q'), Some(Action::Quit));
+        assert_eq!(resolves_to(&config.keymap, 'q'), None);
+    }
+
+    #[test]
+    fn parse_empty_text_gives_defaults() {
+        let config = Config::parse("").unwrap();
+        assert!(config.music_dir.is_none());
+        assert!(config.restore_session);
+        assert_eq!(config.formats, Config::default().formats);
+        assert_eq!(resolves_to(&config.keymap, 'q'), Some(Action::Quit));
+    }
+
+    #[test]
+    fn parse_reports_invalid_settings_with_line_numbers() {
+        assert_eq!(parse_error("shuffle = \"yes\""), "line 1: shuffle must be true or false, not a string");
+        assert_eq!(parse_error("music_dir = 1"), "line 1: music_dir must be a string, not an integer");
+        assert_eq!(parse_error("volume = true"), "line 1: volume must be a number, not a boolean");
+        assert_eq!(parse_error("poll_interval_ms = 2.5"), "line 1: poll_interval_ms must be an integer, not a float");
+        assert_eq!(parse_error("\nrepeat = \"twice\""), "line 2: repeat must be \"off\", \"one\" or \"all\", not \"twice\"");
+        assert_eq!(parse_error("volume = 101"), "line 1: volume must be between 0 and 100, not 101");
+        assert_eq!(parse_error("poll_interval_ms = 0"), "line 1: poll_interval_ms must be between 1 and 1000, not 0");
+        assert_eq!(parse_error("formats = \"mp3\""), "line 1: formats must be an array of strings");
+        assert_eq!(parse_error("formats = [\"mod\"]"), "line 1: unknown format \"mod\" in formats");
+        assert_eq!(parse_error("[theme]\naccent = \"mauve\""), "line 2: unknown color \"mauve\" (use a color name or \"#rrggbb\")");
+        assert_eq!(parse_error("[theme]\nborder = \"red\""), "line 2: unknown theme color \"border\"");
+    }
+
+    #[test]
+    fn parse_reports_invalid_key_bindings() {
+        assert_eq!(parse_error("[keys]\nfly = \"f\""), "line 2: unknown action \"fly\" in [keys]");
+        assert_eq!(parse_error("[keys]\nquit = 1"), "line 2: quit must be a string, not an integer");
+        assert_eq!(parse_error("[keys]\nquit = \"<Nope>\""), "line 2: unknown key <Nope>");
+        assert_eq!(parse_error("[keys]\nquit = \"\""), "line 2: empty key binding");
+        assert_eq!(parse_error("[keys]\nquit = []"), "line 2: empty key binding");
+        assert_eq!(parse_error("[keys]\nquit = \"j\""), "[keys]: \"j\" is bound to both quit and down");
+    }
+
+    #[test]
+    fn parse_reads_eq_presets() {
+        let config = Config::parse("[eq_presets]\n\"My EQ\" = [1, 2.5, -20, 0, 0, 0, 0, 0, 0, 0]\n").unwrap();
+        assert_eq!(config.eq_presets.len(), 1);
+        assert_eq!(config.eq_presets[0].name, "My EQ");
+        // 範囲を超えたゲインは切り詰める
+        assert_eq!(config.eq_presets[0].gains[..3], [1.0, 2.5, -eq::MAX_GAIN]);
+        assert!(!config.eq_presets[0].builtin);
+        assert_eq!(
+            parse_error("[eq_presets]\nloud = [1, 2]"),
+            "line 2: eq preset \"loud\" must be an array of 10 numbers"
+        );
+    }
+
+    #[test]
+    fn saving_eq_presets_keeps_the_rest_of_the_file() {
+        let preset = |name: &str, builtin| EqPreset { name: name.to_string(), gains: [3.0; 10], builtin };
+        let text = "# my settings\nvolume = 40 # quiet\n\n[eq_presets]\nold = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0]\n";
+        let saved = replace_eq_presets(text, &[preset("Flat", true), preset("Late Night", false)]).unwrap();
+        assert!(saved.starts_with("# my settings\nvolume = 40 # quiet\n"), "{}", saved);
+
+        let config = Config::parse(&saved).unwrap();
+        assert_eq!(config.volume, Some(0.4));
+        let names: Vec<&str> = config.eq_presets.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["Late Night"]);
+        assert_eq!(config.eq_presets[0].gains, [3.0; 10]);
+    }
+}
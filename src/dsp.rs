@@ -0,0 +1,607 @@
+// src/dsp.rs
+
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use rodio::Source;
+
+// 音量の自動推定で目標にする RMS (およそ -18 dBFS、ReplayGain の基準と同程度)
+const TARGET_RMS: f32 = 0.125;
+// 自動推定でかける音量の範囲 (-12 dB 〜 +12 dB)
+const MIN_AUTO_GAIN: f32 = 0.25;
+const MAX_AUTO_GAIN: f32 = 4.0;
+// この数のサンプルごとに RMS を測り直す
+const BLOCK_LEN: usize = 2048;
+// 測った RMS をどれだけ平均に混ぜるか (小さいほどゆっくり追従する)
+const BLOCK_WEIGHT: f32 = 0.01;
+// 1サンプルごとに目標の音量へ近づく割合。急な音量変化でのプチノイズを防ぐ
+const GAIN_SMOOTHING: f32 = 0.0001;
+
+enum GainKind {
+    Fixed(f32),
+    Auto {
+        mean_square: Option<f32>,
+        block_sum: f32,
+        block_len: usize,
+        gain: f32,
+        target: f32,
+    },
+}
+
+// f32 に変換済みの音源に音量補正をかける。固定値か、再生しながら聴感上の大きさを推定して合わせるかを選べる
+pub struct Gain<S> {
+    inner: S,
+    kind: GainKind,
+}
+
+impl<S> Gain<S> {
+    pub fn fixed(inner: S, factor: f32) -> Self {
+        Gain { inner, kind: GainKind::Fixed(factor) }
+    }
+
+    pub fn auto(inner: S) -> Self {
+        Gain {
+            inner,
+            kind: GainKind::Auto {
+                mean_square: None,
+                block_sum: 0.0,
+                block_len: 0,
+                gain: 1.0,
+                target: 1.0,
+            },
+        }
+    }
+}
+
+impl<S> Iterator for Gain<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.inner.next()?;
+        match &mut self.kind {
+            GainKind::Fixed(factor) => Some(sample * *factor),
+            GainKind::Auto { mean_square, block_sum, block_len, gain, target } => {
+                *block_sum += sample * sample;
+                *block_len += 1;
+                if *block_len == BLOCK_LEN {
+                    let block = *block_sum / BLOCK_LEN as f32;
+                    // 無音の区間で音量を上げすぎないよう、ほぼ無音のブロックは数えない
+                    if block > 1e-6 {
+                        let ms = match *mean_square {
+                            Some(ms) => ms + (block - ms) * BLOCK_WEIGHT,
+                            None => block,
+                        };
+                        *mean_square = Some(ms);
+                        *target = (TARGET_RMS / ms.sqrt()).clamp(MIN_AUTO_GAIN, MAX_AUTO_GAIN);
+                    }
+                    *block_sum = 0.0;
+                    *block_len = 0;
+                }
+                *gain += (*target - *gain) * GAIN_SMOOTHING;
+                Some((sample * *gain).clamp(-1.0, 1.0))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for Gain<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+// 再生速度を複数のスレッドから読み書きするための値 (f32 をビット列のまま保持する)
+#[derive(Clone)]
+pub struct SharedSpeed(Arc<AtomicU32>);
+
+impl Default for SharedSpeed {
+    fn default() -> Self {
+        SharedSpeed(Arc::new(AtomicU32::new(1.0f32.to_bits())))
+    }
+}
+
+impl SharedSpeed {
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, speed: f32) {
+        self.0.store(speed.to_bits(), Ordering::Relaxed);
+    }
+}
+
+// 音程を変えずに再生速度だけを変える (WSOLA)
+// 入力から窓をかけた区間を少しずつずらしながら取り出して重ね合わせる。取り出す位置は、
+// 直前の区間の自然な続きと波形が最もよく重なる場所を前後に探して決める
+pub struct TimeStretch<S> {
+    inner: S,
+    speed: SharedSpeed,
+    channels: usize,
+    sample_rate: u32,
+    window: Vec<f32>,
+    // 出力側で1回に進めるフレーム数 (窓の半分)
+    hop: usize,
+    // 取り出し位置を前後に探す幅 (フレーム数)
+    tolerance: usize,
+    // まだ捨てていない入力 (チャンネルが交互に並んだサンプル)
+    input: Vec<f32>,
+    // 速度どおりに進めた場合の次の取り出し位置 (input の先頭からのフレーム数)
+    input_pos: f64,
+    // 直前に取り出した区間の開始位置
+    prev: Option<usize>,
+    // 重ね合わせ途中の出力 (窓1つ分)
+    overlap: Vec<f32>,
+    ready: Vec<f32>,
+    ready_pos: usize,
+    inner_done: bool,
+    finished: bool,
+}
+
+impl<S> TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, speed: SharedSpeed) -> Self {
+        let channels = inner.channels().max(1) as usize;
+        let sample_rate = inner.sample_rate();
+        // 窓はおよそ 40ms、探す幅はおよそ 10ms
+        let window_len = ((sample_rate as usize * 40 / 1000) / 2 * 2).max(64);
+        let window = (0..window_len)
+            .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / window_len as f32).cos())
+            .collect();
+        TimeStretch {
+            inner,
+            speed,
+            channels,
+            sample_rate,
+            window,
+            hop: window_len / 2,
+            tolerance: sample_rate as usize / 100,
+            input: Vec::new(),
+            input_pos: 0.0,
+            prev: None,
+            overlap: vec![0.0; window_len * channels],
+            ready: Vec::new(),
+            ready_pos: 0,
+            inner_done: false,
+            finished: false,
+        }
+    }
+
+    fn frames(&self) -> usize {
+        self.input.len() / self.channels
+    }
+
+    // 入力を frames フレーム分たまるまで読み込む
+    fn fill(&mut self, frames: usize) {
+        while !self.inner_done && self.frames() < frames {
+            match self.inner.next() {
+                Some(sample) => self.input.push(sample),
+                None => self.inner_done = true,
+            }
+        }
+    }
+
+    // 2つの区間の波形がどれだけ似ているか (チャンネルを混ぜ、間引いて計算する)
+    fn similarity(&self, a: usize, b: usize, len: usize) -> f32 {
+        let c = self.channels;
+        let mut corr = 0.0;
+        let mut energy = 0.0;
+        for i in (0..len).step_by(4) {
+            let x: f32 = self.input[(a + i) * c..(a + i + 1) * c].iter().sum();
+            let y: f32 = self.input[(b + i) * c..(b + i + 1) * c].iter().sum();
+            corr += x * y;
+            energy += x * x;
+        }
+        corr / (energy + 1e-9).sqrt()
+    }
+
+    // 窓1つ分を重ね合わせ、hop フレーム分の出力を確定させる
+    fn process(&mut self) {
+        let c = self.channels;
+        let window_len = self.window.len();
+        let nominal = self.input_pos.round() as usize;
+        self.fill(nominal + self.tolerance + window_len);
+
+        if self.frames() < nominal + window_len {
+            // 入力が尽きたら、重ね合わせ途中の残りを出して終わる
+            self.ready = std::mem::take(&mut self.overlap);
+            self.ready_pos = 0;
+            self.finished = true;
+            return;
+        }
+
+        let start = match self.prev {
+            None => nominal,
+            Some(prev) => {
+                let natural = prev + self.hop;
+                let lo = nominal.saturating_sub(self.tolerance);
+                let hi = (nominal + self.tolerance).min(self.frames() - window_len);
+                let overlap_len = window_len - self.hop;
+                if natural + overlap_len > self.frames() {
+                    nominal
+                } else {
+                    (lo..=hi)
+                        .map(|a| (self.similarity(a, natural, overlap_len), a))
+                        .max_by(|x, y| x.0.total_cmp(&y.0))
+                        .map_or(nominal, |(_, a)| a)
+                }
+            }
+        };
+
+        for i in 0..window_len {
+            let w = self.window[i];
+            for ch in 0..c {
+                self.overlap[i * c + ch] += self.input[(start + i) * c + ch] * w;
+            }
+        }
+        self.ready.clear();
+        self.ready.extend_from_slice(&self.overlap[..self.hop * c]);
+        self.ready_pos = 0;
+        self.overlap.drain(..self.hop * c);
+        self.overlap.resize(window_len * c, 0.0);
+
+        let speed = self.speed.get().clamp(0.25, 4.0) as f64;
+        self.prev = Some(start);
+        self.input_pos += self.hop as f64 * speed;
+
+        // もう参照しない古い入力を捨てる
+        let keep_from = start.min(self.input_pos as usize).saturating_sub(self.tolerance);
+        if keep_from > 0 {
+            self.input.drain(..keep_from * c);
+            self.input_pos -= keep_from as f64;
+            self.prev = Some(start - keep_from);
+        }
+    }
+}
+
+impl<S> Iterator for TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        loop {
+            if let Some(&sample) = self.ready.get(self.ready_pos) {
+                self.ready_pos += 1;
+                return Some(sample);
+            }
+            if self.finished {
+                return None;
+            }
+            self.process();
+        }
+    }
+}
+
+impl<S> Source for TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// イコライザの各バンドの中心周波数 (Hz)
+pub const EQ_FREQUENCIES: [f32; 10] = [31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0];
+// 1オクターブ幅のピーキングフィルタにする
+const EQ_Q: f32 = std::f32::consts::SQRT_2;
+
+// UI 側で変えたバンドのゲイン (dB) を再生中の音源から読むための共有値
+#[derive(Clone, Default)]
+pub struct SharedEq(Arc<EqState>);
+
+#[derive(Default)]
+struct EqState {
+    gains: [AtomicU32; 10],
+    // ゲインを変えるたびに増やし、音源側は値が変わったときだけ係数を計算し直す
+    version: AtomicU32,
+}
+
+impl SharedEq {
+    pub fn gains(&self) -> [f32; 10] {
+        std::array::from_fn(|i| f32::from_bits(self.0.gains[i].load(Ordering::Relaxed)))
+    }
+
+    pub fn set_gains(&self, gains: [f32; 10]) {
+        for (slot, gain) in self.0.gains.iter().zip(gains) {
+            slot.store(gain.to_bits(), Ordering::Relaxed);
+        }
+        self.0.version.fetch_add(1, Ordering::Release);
+    }
+
+    fn version(&self) -> u32 {
+        self.0.version.load(Ordering::Acquire)
+    }
+}
+
+// 係数を変えても何もしないフィルタ
+const IDENTITY: [f32; 5] = [1.0, 0.0, 0.0, 0.0, 0.0];
+
+// 10バンドのグラフィックイコライザ。全バンドが 0 dB のときは何もしない
+pub struct Equalizer<S> {
+    inner: S,
+    eq: SharedEq,
+    version: Option<u32>,
+    channels: usize,
+    sample_rate: u32,
+    // バンドごとの係数 [b0, b1, b2, a1, a2] (a0 で正規化済み)。0 dB のバンドは IDENTITY
+    coeffs: [[f32; 5]; 10],
+    // すべてのバンドが IDENTITY なら、フィルタを通さない
+    flat: bool,
+    // バンドとチャンネルごとのフィルタの内部状態
+    state: Vec<[f32; 2]>,
+    channel: usize,
+}
+
+impl<S> Equalizer<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, eq: SharedEq) -> Self {
+        let channels = inner.channels().max(1) as usize;
+        let sample_rate = inner.sample_rate();
+        Equalizer {
+            inner,
+            eq,
+            version: None,
+            channels,
+            sample_rate,
+            coeffs: [IDENTITY; 10],
+            flat: true,
+            state: vec![[0.0; 2]; 10 * channels],
+            channel: 0,
+        }
+    }
+
+    // RBJ のクックブックにあるピーキングフィルタの係数を求める
+    // フィルタの内部状態はそのまま引き継ぐ。再生中にゲインを変えるたびに 0 に戻すとプチッと鳴る
+    fn update_coeffs(&mut self) {
+        let gains = self.eq.gains();
+        let nyquist = self.sample_rate as f32 / 2.0;
+        let was_flat = self.flat;
+        self.coeffs = std::array::from_fn(|band| {
+            let (freq, gain) = (EQ_FREQUENCIES[band], gains[band]);
+            // 0 dB のバンドと、サンプルレートに対して高すぎる周波数のバンドは何もしない
+            if gain == 0.0 || freq >= nyquist * 0.9 {
+                return IDENTITY;
+            }
+            let a = 10f32.powf(gain / 40.0);
+            let w0 = 2.0 * std::f32::consts::PI * freq / self.sample_rate as f32;
+            let alpha = w0.sin() / (2.0 * EQ_Q);
+            let cos = w0.cos();
+            let a0 = 1.0 + alpha / a;
+            [
+                (1.0 + alpha * a) / a0,
+                -2.0 * cos / a0,
+                (1.0 - alpha * a) / a0,
+                -2.0 * cos / a0,
+                (1.0 - alpha / a) / a0,
+            ]
+        });
+        self.flat = self.coeffs.iter().all(|c| *c == IDENTITY);
+        // フィルタを通していなかった間の古い状態は使わない
+        if was_flat {
+            self.state.fill([0.0; 2]);
+        }
+    }
+}
+
+impl<S> Iterator for Equalizer<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let mut sample = self.inner.next()?;
+        // 係数の入れ替えはフレームの区切りでだけ行う
+        if self.channel == 0 {
+            let version = self.eq.version();
+            if self.version != Some(version) {
+                self.version = Some(version);
+                self.update_coeffs();
+            }
+        }
+
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % self.channels;
+        if self.flat {
+            return Some(sample);
+        }
+        for (band, c) in self.coeffs.iter().enumerate() {
+            let z = &mut self.state[band * self.channels + channel];
+            let out = c[0] * sample + z[0];
+            z[0] = c[1] * sample - c[3] * out + z[1];
+            z[1] = c[2] * sample - c[4] * out;
+            sample = out;
+        }
+        Some(sample.clamp(-1.0, 1.0))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for Equalizer<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    // frequency Hz の正弦波 (振幅 0.5)。チャンネルには同じ値を入れる
+    fn sine(frequency: f32, sample_rate: u32, channels: u16, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let value = (i as f32 * frequency * std::f32::consts::TAU / sample_rate as f32).sin() * 0.5;
+                std::iter::repeat_n(value, channels as usize)
+            })
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn fixed_gain_scales_every_sample() {
+        let input = sine(440.0, 8_000, 2, 100);
+        let output: Vec<f32> = Gain::fixed(SamplesBuffer::new(2, 8_000, input.clone()), 0.5).collect();
+        assert_eq!(output, input.iter().map(|s| s * 0.5).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn auto_gain_converges_to_the_target_loudness() {
+        // RMS 0.35 の大きな音は下げ、RMS 0.035 の小さな音は上げて、どちらも TARGET_RMS に近づける
+        for amplitude in [0.5, 0.05] {
+            let input: Vec<f32> = sine(440.0, 8_000, 1, 200_000).iter().map(|s| s * 2.0 * amplitude).collect();
+            let output: Vec<f32> = Gain::auto(SamplesBuffer::new(1, 8_000, input)).collect();
+            let settled = rms(&output[output.len() - 8_000..]);
+            assert!((settled / TARGET_RMS - 1.0).abs() < 0.05, "{} for amplitude {}", settled, amplitude);
+        }
+    }
+
+    #[test]
+    fn auto_gain_stays_within_its_limits() {
+        // ごく小さな音でも +12 dB までしか上げず、大きな音も -12 dB までしか下げない
+        for (scale, limit) in [(0.02, MAX_AUTO_GAIN), (2.0, MIN_AUTO_GAIN)] {
+            let input: Vec<f32> = sine(440.0, 8_000, 1, 200_000).iter().map(|s| s * scale).collect();
+            let expected = rms(&input[input.len() - 8_000..]) * limit;
+            let output: Vec<f32> = Gain::auto(SamplesBuffer::new(1, 8_000, input)).collect();
+            let settled = rms(&output[output.len() - 8_000..]);
+            assert!((settled / expected - 1.0).abs() < 0.05, "{} != {}", settled, expected);
+        }
+    }
+
+    // 伸縮したときの出力のフレーム数
+    fn stretched_frames(input: Vec<f32>, channels: u16, speed: f32) -> usize {
+        let shared = SharedSpeed::default();
+        shared.set(speed);
+        let stretch = TimeStretch::new(SamplesBuffer::new(channels, 8_000, input), shared);
+        stretch.count() / channels as usize
+    }
+
+    #[test]
+    fn time_stretch_at_normal_speed_keeps_the_length() {
+        // 8kHz では窓は 320 フレーム
+        let frames = stretched_frames(sine(440.0, 8_000, 2, 8_000), 2, 1.0);
+        assert!(frames.abs_diff(8_000) <= 320, "{}", frames);
+    }
+
+    #[test]
+    fn time_stretch_at_double_speed_halves_the_length() {
+        let frames = stretched_frames(sine(440.0, 8_000, 1, 8_000), 1, 2.0);
+        assert!(frames.abs_diff(4_000) <= 320, "{}", frames);
+        let frames = stretched_frames(sine(440.0, 8_000, 1, 8_000), 1, 0.5);
+        assert!(frames.abs_diff(16_000) <= 320, "{}", frames);
+    }
+
+    #[test]
+    fn time_stretch_finishes_on_short_inputs() {
+        for len in [0, 1, 10, 319, 321] {
+            let input = SamplesBuffer::new(1, 8_000, sine(440.0, 8_000, 1, len));
+            let mut stretch = TimeStretch::new(input, SharedSpeed::default());
+            let frames = stretch.by_ref().take(10_000).count();
+            assert!(frames <= 640, "{} frames from {}", frames, len);
+            assert_eq!(stretch.next(), None);
+        }
+    }
+
+    #[test]
+    fn flat_equalizer_passes_samples_through_unchanged() {
+        let input = sine(440.0, 44_100, 2, 4_410);
+        let eq = SharedEq::default();
+        let output: Vec<f32> = Equalizer::new(SamplesBuffer::new(2, 44_100, input.clone()), eq).collect();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn changing_a_band_keeps_the_filter_state() {
+        // 2kHz のバンドは 50Hz の音をほとんど変えないので、出力は入力とほぼ同じになるはず
+        // ゲインを変えたときに内部状態を 0 に戻すと、その瞬間にステップ応答の雑音が乗る
+        let input = sine(50.0, 8_000, 1, 8_000);
+        let eq = SharedEq::default();
+        let mut gains = [0.0; 10];
+        gains[6] = 6.0;
+        eq.set_gains(gains);
+        let mut equalizer = Equalizer::new(SamplesBuffer::new(1, 8_000, input.clone()), eq.clone());
+
+        let mut worst = 0.0f32;
+        for (i, expected) in input.iter().enumerate() {
+            // 波形の山のあたりでゲインを変える
+            if i > 0 && i % 1_000 == 40 {
+                gains[6] += 1.0;
+                eq.set_gains(gains);
+            }
+            let sample = equalizer.next().unwrap();
+            if i >= 100 {
+                worst = worst.max((sample - expected).abs());
+            }
+        }
+        // 状態を 0 に戻すとおよそ 0.25 ずれる
+        assert!(worst < 0.05, "{}", worst);
+    }
+}
@@ -0,0 +1,97 @@
+// src/eq.rs
+
+use anyhow::Result;
+
+use crate::config;
+
+// バンドごとに変えられるゲインの範囲と、キー1回で変わる量 (dB)
+pub const MAX_GAIN: f32 = 12.0;
+pub const GAIN_STEP: f32 = 1.0;
+
+#[derive(Clone)]
+pub struct EqPreset {
+    pub name: String,
+    pub gains: [f32; 10],
+    // 組み込みのプリセットは削除や上書きができない
+    pub builtin: bool,
+}
+
+fn builtin(name: &str, gains: [f32; 10]) -> EqPreset {
+    EqPreset { name: name.to_string(), gains, builtin: true }
+}
+
+fn builtin_presets() -> Vec<EqPreset> {
+    vec![
+        builtin("Flat", [0.0; 10]),
+        builtin("Bass Boost", [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+        builtin("Vocal", [-2.0, -2.0, -1.0, 0.0, 2.0, 4.0, 4.0, 2.0, 0.0, -1.0]),
+    ]
+}
+
+// イコライザ画面の状態
+pub struct EqPanel {
+    pub is_open: bool,
+    // 選択中のバンド
+    pub band: usize,
+    pub presets: Vec<EqPreset>,
+    // 今のゲインがどのプリセットのままか。バンドをいじったら None
+    pub preset: Option<usize>,
+    // プリセット名を入力中ならその文字列
+    pub naming: Option<String>,
+}
+
+impl EqPanel {
+    // user_presets は設定ファイルの [eq_presets] に保存してあるもの
+    pub fn new(user_presets: Vec<EqPreset>) -> Self {
+        let mut presets = builtin_presets();
+        presets.extend(user_presets);
+        EqPanel {
+            is_open: false,
+            band: 0,
+            presets,
+            preset: Some(0),
+            naming: None,
+        }
+    }
+
+    pub fn preset_name(&self) -> &str {
+        self.preset.and_then(|i| self.presets.get(i)).map_or("Custom", |p| p.name.as_str())
+    }
+
+    // 次のプリセットに切り替え、そのゲインを返す
+    pub fn next_preset(&mut self) -> [f32; 10] {
+        let next = self.preset.map_or(0, |i| (i + 1) % self.presets.len());
+        self.preset = Some(next);
+        self.presets[next].gains
+    }
+
+    // 今のゲインを名前を付けて保存する。同じ名前の利用者プリセットがあれば上書きする
+    pub fn save_preset(&mut self, name: String, gains: [f32; 10]) -> Result<()> {
+        if self.presets.iter().any(|p| p.builtin && p.name == name) {
+            anyhow::bail!("cannot overwrite built-in preset {}", name);
+        }
+        let index = match self.presets.iter().position(|p| p.name == name) {
+            Some(i) => {
+                self.presets[i].gains = gains;
+                i
+            }
+            None => {
+                self.presets.push(EqPreset { name, gains, builtin: false });
+                self.presets.len() - 1
+            }
+        };
+        self.preset = Some(index);
+        config::save_eq_presets(&self.presets)
+    }
+
+    // 選択中の利用者プリセットを消す
+    pub fn delete_preset(&mut self) -> Result<()> {
+        let Some(index) = self.preset else { return Ok(()) };
+        if self.presets[index].builtin {
+            return Ok(());
+        }
+        self.presets.remove(index);
+        self.preset = None;
+        config::save_eq_presets(&self.presets)
+    }
+}
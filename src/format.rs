@@ -0,0 +1,145 @@
+// src/format.rs
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+// 音声ファイルの形式
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AudioFormat {
+    Mp3,
+    Flac,
+    Vorbis,
+    Opus,
+    Wav,
+    // AAC / ALAC などが入った MP4 コンテナ (.m4a)
+    Mp4,
+}
+
+impl AudioFormat {
+    pub const ALL: [AudioFormat; 6] = [
+        AudioFormat::Mp3,
+        AudioFormat::Flac,
+        AudioFormat::Vorbis,
+        AudioFormat::Opus,
+        AudioFormat::Wav,
+        AudioFormat::Mp4,
+    ];
+
+    fn from_extension(path: &Path) -> Option<Self> {
+        Self::from_name(path.extension()?.to_str()?)
+    }
+
+    // 拡張子か形式の名前 (設定ファイルの formats に書くもの) から
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "mp3" => AudioFormat::Mp3,
+            "flac" => AudioFormat::Flac,
+            "ogg" | "oga" | "vorbis" => AudioFormat::Vorbis,
+            "opus" => AudioFormat::Opus,
+            "wav" | "wave" => AudioFormat::Wav,
+            "m4a" | "mp4" | "aac" | "alac" => AudioFormat::Mp4,
+            _ => return None,
+        })
+    }
+}
+
+// ファイルの先頭を読んで形式を判定する。中身で判断できなければ拡張子に頼る
+pub fn detect(path: &Path) -> Option<AudioFormat> {
+    if !path.is_file() {
+        return None;
+    }
+    sniff(path).or_else(|| AudioFormat::from_extension(path))
+}
+
+fn sniff(path: &Path) -> Option<AudioFormat> {
+    let mut file = File::open(path).ok()?;
+    let mut head = [0u8; 64];
+    let mut len = file.read(&mut head).ok()?;
+
+    // ID3v2 タグが前に付いていたら、その後ろを改めて読む
+    if len >= 10 && &head[..3] == b"ID3" {
+        let size = head[6..10].iter().fold(0u64, |acc, &b| (acc << 7) | (b & 0x7f) as u64);
+        let footer = if head[5] & 0x10 != 0 { 10 } else { 0 };
+        file.seek(SeekFrom::Start(10 + size + footer)).ok()?;
+        len = file.read(&mut head).ok()?;
+        // タグだけが付いていて中身が読めなければ MP3 とみなす
+        return Some(sniff_bytes(&head[..len]).unwrap_or(AudioFormat::Mp3));
+    }
+    sniff_bytes(&head[..len])
+}
+
+fn sniff_bytes(head: &[u8]) -> Option<AudioFormat> {
+    if head.starts_with(b"fLaC") {
+        return Some(AudioFormat::Flac);
+    }
+    if head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WAVE" {
+        return Some(AudioFormat::Wav);
+    }
+    if head.len() >= 8 && &head[4..8] == b"ftyp" {
+        return Some(AudioFormat::Mp4);
+    }
+    // Ogg は最初のページの中身でコーデックを見分ける
+    if head.starts_with(b"OggS") && head.len() >= 28 {
+        let segments = head[26] as usize;
+        let payload = head.get(27 + segments..).unwrap_or_default();
+        if payload.starts_with(b"\x01vorbis") {
+            return Some(AudioFormat::Vorbis);
+        }
+        if payload.starts_with(b"OpusHead") {
+            return Some(AudioFormat::Opus);
+        }
+        return None;
+    }
+    // MPEG オーディオのフレーム同期。レイヤーのビットが 00 なら ADTS の AAC
+    if head.len() >= 2 && head[0] == 0xff && head[1] & 0xe0 == 0xe0 {
+        return match (head[1] >> 1) & 0b11 {
+            0b00 => Some(AudioFormat::Mp4),
+            0b01 => Some(AudioFormat::Mp3),
+            _ => None,
+        };
+    }
+    None
+}
+
+// 判定結果を覚えておくキャッシュ。一覧を描画するたびにファイルを開かないようにする
+pub struct FormatCache {
+    entries: HashMap<PathBuf, Option<AudioFormat>>,
+    // 設定で曲として扱うことにしている形式
+    enabled: Vec<AudioFormat>,
+}
+
+impl FormatCache {
+    pub fn new(enabled: Vec<AudioFormat>) -> Self {
+        FormatCache { entries: HashMap::new(), enabled }
+    }
+
+    // 設定で有効にしている形式か
+    pub fn accepts(&self, format: AudioFormat) -> bool {
+        self.enabled.contains(&format)
+    }
+
+    // 曲として扱うファイルか。キャッシュに無ければその場で調べる
+    pub fn is_playable(&self, path: &Path) -> bool {
+        let format = match self.entries.get(path) {
+            Some(format) => *format,
+            None => detect(path),
+        };
+        format.is_some_and(|f| self.accepts(f))
+    }
+
+    pub fn load<'a>(&mut self, paths: impl IntoIterator<Item = &'a PathBuf>) {
+        for path in paths {
+            if !self.entries.contains_key(path) {
+                self.entries.insert(path.clone(), detect(path));
+            }
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<AudioFormat> {
+        self.entries.get(path).copied().flatten()
+    }
+}
@@ -0,0 +1,663 @@
+// src/keymap.rs
+
+use std::fmt;
+
+use anyhow::{anyhow, bail, Result};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+// キーで呼び出せる操作
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Quit,
+    Up,
+    Down,
+    First,
+    Last,
+    Open,
+    Parent,
+    ToggleFocus,
+    Search,
+    SearchNext,
+    SearchPrevious,
+    Filter,
+    RescanLibrary,
+    CycleView,
+    TogglePause,
+    Stop,
+    Next,
+    Previous,
+    SeekBack,
+    SeekForward,
+    SeekBackLong,
+    SeekForwardLong,
+    // 曲の 0%〜90% の位置へ (10% 刻み)
+    SeekPercent(u8),
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    ToggleShuffle,
+    CycleRepeat,
+    Enqueue,
+    EnqueueDir,
+    PlayNext,
+    Remove,
+    MoveUp,
+    MoveDown,
+    ClearQueue,
+    SavePlaylist,
+    ToggleTags,
+    CycleReplayGain,
+    SpeedDown,
+    SpeedUp,
+    SpeedReset,
+    TogglePreservePitch,
+    ToggleCrossfade,
+    CrossfadeShorter,
+    CrossfadeLonger,
+    ToggleEqualizer,
+    CycleVisualizer,
+    ToggleHelp,
+    // イコライザ画面を開いている間だけ使う操作
+    EqNextBand,
+    EqPreviousBand,
+    EqGainDown,
+    EqGainUp,
+    EqResetBand,
+    EqNextPreset,
+    EqSavePreset,
+    EqDeletePreset,
+    EqClose,
+    // ヘルプを開いている間だけ使う操作 (割り当ての無いキーでヘルプを閉じる)
+    HelpScrollDown,
+    HelpScrollUp,
+}
+
+// どの画面でのキー割り当てか。画面が違えば同じキーを別の操作に割り当てられる
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Context {
+    Main,
+    Equalizer,
+    Help,
+}
+
+// 操作ごとの、設定ファイルで使う名前と既定のキー
+const ACTIONS: &[(Action, &str, &[&str])] = &[
+    (Action::Quit, "quit", &["q"]),
+    (Action::Up, "up", &["k", "<Up>"]),
+    (Action::Down, "down", &["j", "<Down>"]),
+    (Action::First, "first", &["gg"]),
+    (Action::Last, "last", &["G"]),
+    (Action::Open, "open", &["l", "<Enter>"]),
+    (Action::Parent, "parent", &["h"]),
+    (Action::ToggleFocus, "focus", &["<Tab>"]),
+    (Action::Search, "search", &["/"]),
+    (Action::SearchNext, "search_next", &["n"]),
+    (Action::SearchPrevious, "search_previous", &["N"]),
+    (Action::Filter, "filter", &["F"]),
+    (Action::RescanLibrary, "rescan_library", &["u"]),
+    (Action::CycleView, "view", &["b"]),
+    (Action::TogglePause, "pause", &["s"]),
+    (Action::Stop, "stop", &["<Esc>"]),
+    (Action::Next, "next", &[">"]),
+    (Action::Previous, "previous", &["<"]),
+    (Action::SeekBack, "seek_back", &["<Left>"]),
+    (Action::SeekForward, "seek_forward", &["<Right>"]),
+    (Action::SeekBackLong, "seek_back_long", &["H"]),
+    (Action::SeekForwardLong, "seek_forward_long", &["L"]),
+    (Action::SeekPercent(0), "seek_0", &["0"]),
+    (Action::SeekPercent(1), "seek_10", &["1"]),
+    (Action::SeekPercent(2), "seek_20", &["2"]),
+    (Action::SeekPercent(3), "seek_30", &["3"]),
+    (Action::SeekPercent(4), "seek_40", &["4"]),
+    (Action::SeekPercent(5), "seek_50", &["5"]),
+    (Action::SeekPercent(6), "seek_60", &["6"]),
+    (Action::SeekPercent(7), "seek_70", &["7"]),
+    (Action::SeekPercent(8), "seek_80", &["8"]),
+    (Action::SeekPercent(9), "seek_90", &["9"]),
+    (Action::VolumeUp, "volume_up", &["+", "="]),
+    (Action::VolumeDown, "volume_down", &["-"]),
+    (Action::Mute, "mute", &["m"]),
+    (Action::ToggleShuffle, "shuffle", &["d"]),
+    (Action::CycleRepeat, "repeat", &["r"]),
+    (Action::Enqueue, "enqueue", &["a"]),
+    (Action::EnqueueDir, "enqueue_dir", &["A"]),
+    (Action::PlayNext, "play_next", &["i"]),
+    (Action::Remove, "remove", &["x"]),
+    (Action::MoveUp, "move_up", &["K"]),
+    (Action::MoveDown, "move_down", &["J"]),
+    (Action::ClearQueue, "clear_queue", &["C"]),
+    (Action::SavePlaylist, "save_playlist", &["w"]),
+    (Action::ToggleTags, "toggle_tags", &["t"]),
+    (Action::CycleReplayGain, "replay_gain", &["R"]),
+    (Action::SpeedDown, "speed_down", &["["]),
+    (Action::SpeedUp, "speed_up", &["]"]),
+    (Action::SpeedReset, "speed_reset", &["\\"]),
+    (Action::TogglePreservePitch, "preserve_pitch", &["P"]),
+    (Action::ToggleCrossfade, "crossfade", &["c"]),
+    (Action::CrossfadeShorter, "crossfade_shorter", &["("]),
+    (Action::CrossfadeLonger, "crossfade_longer", &[")"]),
+    (Action::ToggleEqualizer, "equalizer", &["e"]),
+    (Action::CycleVisualizer, "visualizer", &["v"]),
+    (Action::ToggleHelp, "help", &["?"]),
+    (Action::EqNextBand, "eq_next_band", &["j", "<Down>"]),
+    (Action::EqPreviousBand, "eq_previous_band", &["k", "<Up>"]),
+    (Action::EqGainDown, "eq_gain_down", &["h", "<Left>"]),
+    (Action::EqGainUp, "eq_gain_up", &["l", "<Right>"]),
+    (Action::EqResetBand, "eq_reset_band", &["0"]),
+    (Action::EqNextPreset, "eq_preset", &["p"]),
+    (Action::EqSavePreset, "eq_save_preset", &["n"]),
+    (Action::EqDeletePreset, "eq_delete_preset", &["D"]),
+    (Action::EqClose, "eq_close", &["e", "<Esc>"]),
+    (Action::HelpScrollDown, "help_down", &["j", "<Down>"]),
+    (Action::HelpScrollUp, "help_up", &["k", "<Up>"]),
+];
+
+// ヘルプで操作をまとめる分類 (表示順)
+const CATEGORIES: &[&str] = &["General", "Browse", "Playback", "Queue", "Sound", "Display", "Equalizer", "Help"];
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        ACTIONS.iter().find(|(a, _, _)| *a == self).map_or("", |(_, name, _)| name)
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        ACTIONS.iter().find(|(_, n, _)| *n == name).map(|(a, _, _)| *a)
+    }
+
+    pub fn context(self) -> Context {
+        match self.category() {
+            "Equalizer" => Context::Equalizer,
+            "Help" => Context::Help,
+            _ => Context::Main,
+        }
+    }
+
+    fn category(self) -> &'static str {
+        match self {
+            Action::Quit | Action::ToggleHelp => "General",
+            Action::Up
+            | Action::Down
+            | Action::First
+            | Action::Last
+            | Action::Open
+            | Action::Parent
+            | Action::ToggleFocus
+            | Action::Search
+            | Action::SearchNext
+            | Action::SearchPrevious
+            | Action::Filter
+            | Action::RescanLibrary
+            | Action::CycleView => "Browse",
+            Action::TogglePause
+            | Action::Stop
+            | Action::Next
+            | Action::Previous
+            | Action::SeekBack
+            | Action::SeekForward
+            | Action::SeekBackLong
+            | Action::SeekForwardLong
+            | Action::SeekPercent(_)
+            | Action::ToggleShuffle
+            | Action::CycleRepeat => "Playback",
+            Action::Enqueue
+            | Action::EnqueueDir
+            | Action::PlayNext
+            | Action::Remove
+            | Action::MoveUp
+            | Action::MoveDown
+            | Action::ClearQueue
+            | Action::SavePlaylist => "Queue",
+            Action::VolumeUp
+            | Action::VolumeDown
+            | Action::Mute
+            | Action::CycleReplayGain
+            | Action::SpeedDown
+            | Action::SpeedUp
+            | Action::SpeedReset
+            | Action::TogglePreservePitch
+            | Action::ToggleCrossfade
+            | Action::CrossfadeShorter
+            | Action::CrossfadeLonger
+            | Action::ToggleEqualizer => "Sound",
+            Action::ToggleTags | Action::CycleVisualizer => "Display",
+            Action::EqNextBand
+            | Action::EqPreviousBand
+            | Action::EqGainDown
+            | Action::EqGainUp
+            | Action::EqResetBand
+            | Action::EqNextPreset
+            | Action::EqSavePreset
+            | Action::EqDeletePreset
+            | Action::EqClose => "Equalizer",
+            Action::HelpScrollDown | Action::HelpScrollUp => "Help",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Action::Quit => "Quit",
+            Action::ToggleHelp => "Show or hide this help",
+            Action::Up => "Move up",
+            Action::Down => "Move down",
+            Action::First => "Go to the first item",
+            Action::Last => "Go to the last item",
+            Action::Open => "Open directory / play",
+            Action::Parent => "Go to the parent directory",
+            Action::ToggleFocus => "Switch between browser and queue",
+            Action::Search => "Search the file list",
+            Action::SearchNext => "Next search match",
+            Action::SearchPrevious => "Previous search match",
+            Action::Filter => "Filter the file list (fuzzy)",
+            Action::RescanLibrary => "Rescan the music library",
+            Action::CycleView => "Cycle view (folders / artists / genres / years)",
+            Action::TogglePause => "Pause / resume",
+            Action::Stop => "Stop",
+            Action::Next => "Next track",
+            Action::Previous => "Previous track",
+            Action::SeekBack => "Seek back 5s",
+            Action::SeekForward => "Seek forward 5s",
+            Action::SeekBackLong => "Seek back 30s",
+            Action::SeekForwardLong => "Seek forward 30s",
+            Action::SeekPercent(_) => "Seek to 0%-90%",
+            Action::ToggleShuffle => "Toggle shuffle",
+            Action::CycleRepeat => "Cycle repeat (off / all / one)",
+            Action::Enqueue => "Add to queue",
+            Action::EnqueueDir => "Add the whole directory to queue",
+            Action::PlayNext => "Play next",
+            Action::Remove => "Remove from queue",
+            Action::MoveUp => "Move queue item up",
+            Action::MoveDown => "Move queue item down",
+            Action::ClearQueue => "Clear queue",
+            Action::SavePlaylist => "Save queue as an M3U8 playlist",
+            Action::VolumeUp => "Volume up",
+            Action::VolumeDown => "Volume down",
+            Action::Mute => "Mute",
+            Action::CycleReplayGain => "Cycle ReplayGain (off / track / album)",
+            Action::SpeedDown => "Slower",
+            Action::SpeedUp => "Faster",
+            Action::SpeedReset => "Reset speed",
+            Action::TogglePreservePitch => "Toggle pitch-preserving speed",
+            Action::ToggleCrossfade => "Toggle crossfade",
+            Action::CrossfadeShorter => "Shorter crossfade",
+            Action::CrossfadeLonger => "Longer crossfade",
+            Action::ToggleEqualizer => "Equalizer",
+            Action::ToggleTags => "Toggle tag / file name display",
+            Action::CycleVisualizer => "Cycle visualizer (spectrum / scope / off)",
+            Action::EqNextBand => "Next band",
+            Action::EqPreviousBand => "Previous band",
+            Action::EqGainDown => "Lower the band gain",
+            Action::EqGainUp => "Raise the band gain",
+            Action::EqResetBand => "Reset the band to 0 dB",
+            Action::EqNextPreset => "Next preset",
+            Action::EqSavePreset => "Save the gains as a preset",
+            Action::EqDeletePreset => "Delete the user preset",
+            Action::EqClose => "Close the equalizer",
+            Action::HelpScrollDown => "Scroll down",
+            Action::HelpScrollUp => "Scroll up",
+        }
+    }
+}
+
+// 修飾キーを含めた1回のキー入力
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeyPress {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl KeyPress {
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        // 文字キーの Shift は大文字かどうかに含まれているので、修飾キーとしては見ない
+        let mut modifiers = modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT | KeyModifiers::SHIFT);
+        if matches!(code, KeyCode::Char(_) | KeyCode::BackTab) {
+            modifiers.remove(KeyModifiers::SHIFT);
+        }
+        KeyPress { code, modifiers }
+    }
+}
+
+impl From<KeyEvent> for KeyPress {
+    fn from(event: KeyEvent) -> Self {
+        KeyPress::new(event.code, event.modifiers)
+    }
+}
+
+// 設定ファイルと同じ書き方で表示する
+impl fmt::Display for KeyPress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.code {
+            KeyCode::Char(' ') => "Space".to_string(),
+            KeyCode::Char('<') => "lt".to_string(),
+            KeyCode::Char(c) if self.modifiers.is_empty() => return write!(f, "{}", c),
+            KeyCode::Char(c) => c.to_string(),
+            KeyCode::F(n) => format!("F{}", n),
+            code => KEY_NAMES
+                .iter()
+                .find(|(_, c)| *c == code)
+                .map_or("?".to_string(), |(name, _)| name.to_string()),
+        };
+        let mut prefix = String::new();
+        for (modifier, letter) in [(KeyModifiers::CONTROL, "C-"), (KeyModifiers::ALT, "A-"), (KeyModifiers::SHIFT, "S-")] {
+            if self.modifiers.contains(modifier) {
+                prefix.push_str(letter);
+            }
+        }
+        write!(f, "<{}{}>", prefix, name)
+    }
+}
+
+// <...> の中で使えるキーの名前
+const KEY_NAMES: &[(&str, KeyCode)] = &[
+    ("Enter", KeyCode::Enter),
+    ("Esc", KeyCode::Esc),
+    ("Tab", KeyCode::Tab),
+    ("BackTab", KeyCode::BackTab),
+    ("Backspace", KeyCode::Backspace),
+    ("Up", KeyCode::Up),
+    ("Down", KeyCode::Down),
+    ("Left", KeyCode::Left),
+    ("Right", KeyCode::Right),
+    ("Home", KeyCode::Home),
+    ("End", KeyCode::End),
+    ("PageUp", KeyCode::PageUp),
+    ("PageDown", KeyCode::PageDown),
+    ("Insert", KeyCode::Insert),
+    ("Del", KeyCode::Delete),
+];
+
+// キーの並び。"gg" のような文字の並びに、"<C-d>" や "<Enter>" のような名前付きのキーを混ぜて書ける
+pub fn parse_sequence(text: &str) -> Result<Vec<KeyPress>> {
+    let mut keys = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        // "<" の後に閉じる ">" が無ければ、"<" そのものとして扱う
+        if c == '<'
+            && let Some(end) = rest[1..].find('>')
+            && end > 0
+        {
+            keys.push(parse_named(&rest[1..end + 1])?);
+            rest = &rest[end + 2..];
+        } else {
+            keys.push(KeyPress::new(KeyCode::Char(c), KeyModifiers::NONE));
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    if keys.is_empty() {
+        bail!("empty key binding");
+    }
+    Ok(keys)
+}
+
+// "C-d" や "A-S-Left" のような、<> の中身
+fn parse_named(text: &str) -> Result<KeyPress> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut name = text;
+    while let Some((prefix, rest)) = name.split_once('-')
+        && !rest.is_empty()
+    {
+        modifiers |= match prefix.to_ascii_uppercase().as_str() {
+            "C" => KeyModifiers::CONTROL,
+            "A" | "M" => KeyModifiers::ALT,
+            "S" => KeyModifiers::SHIFT,
+            _ => bail!("unknown modifier \"{}\" in <{}>", prefix, text),
+        };
+        name = rest;
+    }
+    let mut chars = name.chars();
+    let code = match (chars.next(), chars.next()) {
+        (Some(c), None) => KeyCode::Char(c),
+        _ if name.eq_ignore_ascii_case("space") => KeyCode::Char(' '),
+        _ if name.eq_ignore_ascii_case("lt") => KeyCode::Char('<'),
+        // 先頭が多バイト文字でも切り出しで落ちないよう、文字として取り除く
+        _ if let Some(number) = name.strip_prefix(['f', 'F'])
+            && let Ok(number) = number.parse::<u8>() =>
+        {
+            KeyCode::F(number)
+        }
+        _ => KEY_NAMES
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, code)| *code)
+            .ok_or_else(|| anyhow!("unknown key <{}>", text))?,
+    };
+    Ok(KeyPress::new(code, modifiers))
+}
+
+pub fn format_sequence(keys: &[KeyPress]) -> String {
+    keys.iter().map(|k| k.to_string()).collect()
+}
+
+// 押しかけのキーの並びに対する結果
+pub enum Resolution {
+    Action(Action),
+    // 続きのキーを待っている
+    Pending,
+    None,
+}
+
+pub struct Keymap {
+    bindings: Vec<(Vec<KeyPress>, Action)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::new(&[]).expect("default keymap has no conflicts")
+    }
+}
+
+impl Keymap {
+    // 既定の割り当てに、設定ファイルで指定した割り当てを上書きする
+    // 設定した操作は既定のキーをすべて外し、指定したキーだけで動く
+    pub fn new(overrides: &[(Action, Vec<Vec<KeyPress>>)]) -> Result<Self> {
+        let mut bindings = Vec::new();
+        for (action, _, defaults) in ACTIONS {
+            match overrides.iter().find(|(a, _)| a == action) {
+                Some((_, keys)) => bindings.extend(keys.iter().map(|k| (k.clone(), *action))),
+                None => {
+                    for text in *defaults {
+                        bindings.push((parse_sequence(text)?, *action));
+                    }
+                }
+            }
+        }
+
+        // 同じ画面の中で、同じキーの並びや、片方がもう片方の先頭と同じになる並びは区別できない
+        for (i, (keys, action)) in bindings.iter().enumerate() {
+            for (other_keys, other) in &bindings[i + 1..] {
+                if action.context() != other.context() {
+                    continue;
+                }
+                let shorter = keys.len().min(other_keys.len());
+                if keys[..shorter] == other_keys[..shorter] {
+                    let (a, b) = if keys.len() <= other_keys.len() { (keys, other_keys) } else { (other_keys, keys) };
+                    if a == b {
+                        bail!("\"{}\" is bound to both {} and {}", format_sequence(a), action.name(), other.name());
+                    }
+                    bail!(
+                        "\"{}\" ({}) is the start of \"{}\" ({})",
+                        format_sequence(a),
+                        if a == keys { action.name() } else { other.name() },
+                        format_sequence(b),
+                        if b == keys { action.name() } else { other.name() },
+                    );
+                }
+            }
+        }
+        Ok(Keymap { bindings })
+    }
+
+    // ヘルプに載せる (分類, [(キー, 説明)]) の一覧。今の割り当てから作る
+    // 0〜9 の位置へのシークのように説明が同じ操作は1行にまとめる
+    pub fn help(&self) -> Vec<(&'static str, Vec<(String, &'static str)>)> {
+        let mut groups: Vec<(&'static str, Vec<(String, &'static str)>)> =
+            CATEGORIES.iter().map(|c| (*c, Vec::new())).collect();
+        for (action, _, _) in ACTIONS {
+            let keys: Vec<String> = self
+                .bindings
+                .iter()
+                .filter(|(_, a)| a == action)
+                .map(|(keys, _)| format_sequence(keys))
+                .collect();
+            if keys.is_empty() {
+                continue;
+            }
+            let Some((_, rows)) = groups.iter_mut().find(|(c, _)| *c == action.category()) else { continue };
+            match rows.iter_mut().find(|(_, d)| *d == action.description()) {
+                Some((existing, _)) => {
+                    existing.push(' ');
+                    existing.push_str(&keys.join(" "));
+                }
+                None => rows.push((keys.join(" "), action.description())),
+            }
+        }
+        groups.retain(|(_, rows)| !rows.is_empty());
+        groups
+    }
+
+    // 操作に割り当てた最初のキーの並び。画面の中の操作案内に使う
+    pub fn first_key(&self, action: Action) -> String {
+        self.bindings
+            .iter()
+            .find(|(_, a)| *a == action)
+            .map_or_else(String::new, |(keys, _)| format_sequence(keys))
+    }
+
+    pub fn resolve(&self, context: Context, pending: &[KeyPress]) -> Resolution {
+        let mut resolution = Resolution::None;
+        for (keys, action) in self.bindings.iter().filter(|(_, a)| a.context() == context) {
+            if keys.as_slice() == pending {
+                return Resolution::Action(*action);
+            }
+            if keys.starts_with(pending) {
+                resolution = Resolution::Pending;
+            }
+        }
+        resolution
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyPress {
+        KeyPress::new(code, modifiers)
+    }
+
+    fn char_key(c: char) -> KeyPress {
+        key(KeyCode::Char(c), KeyModifiers::NONE)
+    }
+
+    fn keys(text: &str) -> Vec<Vec<KeyPress>> {
+        vec![parse_sequence(text).unwrap()]
+    }
+
+    fn error(overrides: &[(Action, Vec<Vec<KeyPress>>)]) -> String {
+        match Keymap::new(overrides) {
+            Ok(_) => panic!("expected a conflict"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_sequence_mixes_characters_and_named_keys() {
+        assert_eq!(parse_sequence("gg").unwrap(), [char_key('g'), char_key('g')]);
+        assert_eq!(parse_sequence("<Enter>").unwrap(), [key(KeyCode::Enter, KeyModifiers::NONE)]);
+        assert_eq!(
+            parse_sequence("g<C-d>").unwrap(),
+            [char_key('g'), key(KeyCode::Char('d'), KeyModifiers::CONTROL)]
+        );
+        assert_eq!(
+            parse_sequence("<a-s-left>").unwrap(),
+            [key(KeyCode::Left, KeyModifiers::ALT | KeyModifiers::SHIFT)]
+        );
+        assert_eq!(parse_sequence("<M-x>").unwrap(), [key(KeyCode::Char('x'), KeyModifiers::ALT)]);
+        assert_eq!(parse_sequence("<F12>").unwrap(), [key(KeyCode::F(12), KeyModifiers::NONE)]);
+        assert_eq!(parse_sequence("<Space><lt>").unwrap(), [char_key(' '), char_key('<')]);
+        // 文字キーの Shift は大文字に含まれているので外す
+        assert_eq!(parse_sequence("<S-G>").unwrap(), [char_key('G')]);
+    }
+
+    #[test]
+    fn parse_sequence_treats_unclosed_angle_bracket_as_a_character() {
+        assert_eq!(parse_sequence("<").unwrap(), [char_key('<')]);
+        assert_eq!(parse_sequence("<>").unwrap(), [char_key('<'), char_key('>')]);
+        assert_eq!(parse_sequence("é<").unwrap(), [char_key('é'), char_key('<')]);
+    }
+
+    #[test]
+    fn parse_sequence_rejects_unknown_keys() {
+        let error = |text: &str| parse_sequence(text).unwrap_err().to_string();
+        assert_eq!(error(""), "empty key binding");
+        assert_eq!(error("<Nope>"), "unknown key <Nope>");
+        assert_eq!(error("<X-a>"), "unknown modifier \"X\" in <X-a>");
+        // 多バイト文字で始まる名前でも落ちない
+        assert_eq!(error("<éx>"), "unknown key <éx>");
+        assert_eq!(error("<Fé>"), "unknown key <Fé>");
+    }
+
+    #[test]
+    fn format_sequence_round_trips() {
+        for text in ["gg", "<Enter>", "<C-d>", "<A-S-Left>", "<F5>", "<Space>", "<lt>", "G"] {
+            assert_eq!(format_sequence(&parse_sequence(text).unwrap()), text);
+        }
+    }
+
+    #[test]
+    fn default_keymap_has_no_conflicts() {
+        assert!(Keymap::new(&[]).is_ok());
+    }
+
+    #[test]
+    fn new_rejects_the_same_sequence_for_two_actions() {
+        assert_eq!(error(&[(Action::Quit, keys("j"))]), "\"j\" is bound to both quit and down");
+    }
+
+    #[test]
+    fn new_rejects_a_sequence_that_starts_another() {
+        assert_eq!(error(&[(Action::Quit, keys("g"))]), "\"g\" (quit) is the start of \"gg\" (first)");
+        assert_eq!(error(&[(Action::Quit, keys("jx"))]), "\"j\" (down) is the start of \"jx\" (quit)");
+    }
+
+    #[test]
+    fn new_allows_the_same_key_on_different_screens() {
+        // 既定でも j は一覧・イコライザ・ヘルプでそれぞれ別の操作
+        let keymap = Keymap::new(&[(Action::EqClose, keys("q"))]).unwrap();
+        assert!(matches!(keymap.resolve(Context::Main, &[char_key('q')]), Resolution::Action(Action::Quit)));
+        assert!(matches!(keymap.resolve(Context::Equalizer, &[char_key('q')]), Resolution::Action(Action::EqClose)));
+        // 同じ画面の中ではやはり衝突になる
+        assert_eq!(
+            error(&[(Action::EqClose, keys("j"))]),
+            "\"j\" is bound to both eq_next_band and eq_close"
+        );
+    }
+
+    #[test]
+    fn overriding_an_action_removes_its_default_keys() {
+        let keymap = Keymap::new(&[(Action::Quit, vec![parse_sequence("Q").unwrap(), parse_sequence("<C-c>").unwrap()])])
+            .unwrap();
+        assert!(matches!(keymap.resolve(Context::Main, &[char_key('q')]), Resolution::None));
+        assert!(matches!(keymap.resolve(Context::Main, &[char_key('Q')]), Resolution::Action(Action::Quit)));
+        assert_eq!(keymap.first_key(Action::Quit), "Q");
+    }
+
+    #[test]
+    fn resolve_waits_for_the_rest_of_a_sequence() {
+        let keymap = Keymap::default();
+        assert!(matches!(keymap.resolve(Context::Main, &[char_key('g')]), Resolution::Pending));
+        assert!(matches!(
+            keymap.resolve(Context::Main, &[char_key('g'), char_key('g')]),
+            Resolution::Action(Action::First)
+        ));
+        assert!(matches!(keymap.resolve(Context::Main, &[char_key('g'), char_key('x')]), Resolution::None));
+        assert!(matches!(keymap.resolve(Context::Help, &[char_key('q')]), Resolution::None));
+    }
+
+    #[test]
+    fn action_names_round_trip() {
+        for (action, name, _) in ACTIONS {
+            assert_eq!(Action::from_name(name), Some(*action));
+            assert_eq!(action.name(), *name);
+        }
+    }
+}
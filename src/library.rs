@@ -0,0 +1,306 @@
+// src/library.rs
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use crate::{playback, queue, tags::{self, Tags}};
+
+// 保存形式を変えたら上げる。古い形式の一覧は捨ててすべて読み直す
+const FORMAT_VERSION: u32 = 3;
+
+// ライブラリに登録した1曲
+#[derive(Clone)]
+pub struct Track {
+    pub path: PathBuf,
+    // 読んだときのファイルの更新時刻 (UNIX エポックから) と大きさ。どちらかが変わったら読み直す
+    pub modified: Duration,
+    pub size: u64,
+    pub tags: Tags,
+}
+
+// 音楽フォルダ以下のすべての曲と、そのタグや長さの一覧
+// ディスクに保存しておき、再スキャンでは新しいファイルと変わったファイルだけを読む
+#[derive(Clone, Default)]
+pub struct Library {
+    root: PathBuf,
+    tracks: BTreeMap<PathBuf, Track>,
+}
+
+impl Library {
+    // 保存してある一覧を読む。無い・別のフォルダのもの・古い形式なら空の一覧から始める
+    pub fn load(root: &Path) -> Self {
+        match library_file().and_then(|p| fs::read_to_string(p).ok()) {
+            Some(content) => Library::parse(root, &content),
+            None => Library { root: root.to_path_buf(), tracks: BTreeMap::new() },
+        }
+    }
+
+    fn parse(root: &Path, content: &str) -> Self {
+        let mut library = Library { root: root.to_path_buf(), tracks: BTreeMap::new() };
+        let mut lines = content.lines();
+        if lines.next() != Some(format!("version={}", FORMAT_VERSION).as_str())
+            || lines.next() != Some(format!("root={}", root.display()).as_str())
+        {
+            return library;
+        }
+        let mut current: Option<Track> = None;
+        for line in lines {
+            let Some((key, value)) = line.split_once('=') else { continue };
+            if key == "path" {
+                if let Some(track) = current.take() {
+                    library.tracks.insert(track.path.clone(), track);
+                }
+                current = Some(Track {
+                    path: PathBuf::from(value),
+                    modified: Duration::ZERO,
+                    size: 0,
+                    tags: Tags::default(),
+                });
+                continue;
+            }
+            let Some(track) = &mut current else { continue };
+            let tags = &mut track.tags;
+            match key {
+                "modified" => track.modified = value.parse().map(Duration::from_nanos).unwrap_or_default(),
+                "size" => track.size = value.parse().unwrap_or_default(),
+                "title" => tags.title = Some(value.to_string()),
+                "artist" => tags.artist = Some(value.to_string()),
+                "album" => tags.album = Some(value.to_string()),
+                "genre" => tags.genre = Some(value.to_string()),
+                "track" => tags.track = value.parse().ok(),
+                "year" => tags.year = value.parse().ok(),
+                "duration" => tags.duration = value.parse().ok().and_then(|s| Duration::try_from_secs_f64(s).ok()),
+                "track_gain" => tags.track_gain = value.parse().ok(),
+                "album_gain" => tags.album_gain = value.parse().ok(),
+                "track_peak" => tags.track_peak = value.parse().ok(),
+                "album_peak" => tags.album_peak = value.parse().ok(),
+                _ => {}
+            }
+        }
+        if let Some(track) = current {
+            library.tracks.insert(track.path.clone(), track);
+        }
+        library
+    }
+
+    // ディスクを読まずに曲の一覧から作る (ほかのモジュールのテスト用)
+    #[cfg(test)]
+    pub fn from_tracks(tracks: impl IntoIterator<Item = Track>) -> Self {
+        let tracks = tracks.into_iter().map(|t| (t.path.clone(), t)).collect();
+        Library { root: PathBuf::new(), tracks }
+    }
+
+    // パスの順に並んだすべての曲
+    pub fn tracks(&self) -> impl Iterator<Item = &Track> {
+        self.tracks.values()
+    }
+
+    // root 以下をたどり直して一覧を最新にする
+    // 前回から更新時刻も大きさも変わっていないファイルは、タグを読まずに前回の内容を使う
+    pub fn scan(&mut self, is_track: impl Fn(&Path) -> bool + Copy) {
+        let mut tracks = BTreeMap::new();
+        for path in queue::collect_tracks(&self.root, is_track) {
+            let Ok(metadata) = fs::metadata(&path) else { continue };
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            let size = metadata.len();
+            let track = match self.tracks.remove(&path) {
+                Some(track) if track.modified == modified && track.size == size => track,
+                _ => read_track(path.clone(), modified, size),
+            };
+            tracks.insert(path, track);
+        }
+        // 残っているのは消えたファイル
+        self.tracks = tracks;
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = library_file() else { return Ok(()) };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_text()?)?;
+        Ok(())
+    }
+
+    fn to_text(&self) -> Result<String> {
+        let mut content = format!("version={}\nroot={}\n", FORMAT_VERSION, self.root.display());
+        for track in self.tracks.values() {
+            let tags = &track.tags;
+            writeln!(content, "path={}", track.path.display())?;
+            writeln!(content, "modified={}", track.modified.as_nanos())?;
+            writeln!(content, "size={}", track.size)?;
+            let fields = [
+                ("title", tags.title.clone()),
+                ("artist", tags.artist.clone()),
+                ("album", tags.album.clone()),
+                ("genre", tags.genre.clone()),
+                ("track", tags.track.map(|n| n.to_string())),
+                ("year", tags.year.map(|n| n.to_string())),
+                ("duration", tags.duration.map(|d| d.as_secs_f64().to_string())),
+                ("track_gain", tags.track_gain.map(|g| g.to_string())),
+                ("album_gain", tags.album_gain.map(|g| g.to_string())),
+                ("track_peak", tags.track_peak.map(|p| p.to_string())),
+                ("album_peak", tags.album_peak.map(|p| p.to_string())),
+            ];
+            for (key, value) in fields {
+                // 1行1項目の形式なので、タグの中の改行は空白にする
+                if let Some(value) = value {
+                    writeln!(content, "{}={}", key, value.replace(['\r', '\n'], " "))?;
+                }
+            }
+        }
+        Ok(content)
+    }
+}
+
+// タグと長さを読む。タグに長さが無ければデコーダで調べる
+fn read_track(path: PathBuf, modified: Duration, size: u64) -> Track {
+    let mut tags = tags::read_tags(&path).unwrap_or_default();
+    if tags.duration.is_none() {
+        tags.duration = playback::probe_duration(&path);
+    }
+    Track { path, modified, size, tags }
+}
+
+// ファイルから作り直せる一覧なので、キャッシュディレクトリの music_cli/library に置く
+fn library_file() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("music_cli").join("library"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("music_cli_library_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("album")).unwrap();
+        root
+    }
+
+    fn is_track(path: &Path) -> bool {
+        path.extension().is_some_and(|e| e == "mp3")
+    }
+
+    fn modified(path: &Path) -> Duration {
+        fs::metadata(path).unwrap().modified().unwrap().duration_since(UNIX_EPOCH).unwrap()
+    }
+
+    // 読み直されたかどうかが分かるよう、前回の内容として "cached" という題名を入れておく
+    fn cached(path: PathBuf, modified: Duration, size: u64) -> Track {
+        let tags = Tags { title: Some("cached".to_string()), ..Tags::default() };
+        Track { path, modified, size, tags }
+    }
+
+    #[test]
+    fn to_text_then_parse_round_trips() {
+        let root = Path::new("/music");
+        let mut library = Library { root: root.to_path_buf(), tracks: BTreeMap::new() };
+        let tags = Tags {
+            title: Some("Two\nLines".to_string()),
+            artist: Some("Band".to_string()),
+            album: Some("Album".to_string()),
+            genre: Some("Rock".to_string()),
+            track: Some(3),
+            year: Some(1999),
+            duration: Some(Duration::from_millis(215_500)),
+            track_gain: Some(-6.5),
+            album_gain: Some(-7.25),
+            track_peak: Some(0.98),
+            album_peak: Some(1.0),
+        };
+        let tagged = Track {
+            path: PathBuf::from("/music/a=b.mp3"),
+            modified: Duration::new(1_700_000_000, 123_456_789),
+            size: 4_096,
+            tags,
+        };
+        let untagged = cached(PathBuf::from("/music/untagged.mp3"), Duration::ZERO, 0);
+        for track in [tagged, untagged] {
+            library.tracks.insert(track.path.clone(), track);
+        }
+
+        let loaded = Library::parse(root, &library.to_text().unwrap());
+        let tracks: Vec<&Track> = loaded.tracks().collect();
+        assert_eq!(tracks.len(), 2);
+        let (track, tags) = (tracks[0], &tracks[0].tags);
+        assert_eq!(track.path, Path::new("/music/a=b.mp3"));
+        assert_eq!(track.modified, Duration::new(1_700_000_000, 123_456_789));
+        assert_eq!(track.size, 4_096);
+        // 改行は空白になる
+        assert_eq!(tags.title.as_deref(), Some("Two Lines"));
+        assert_eq!(tags.artist.as_deref(), Some("Band"));
+        assert_eq!(tags.album.as_deref(), Some("Album"));
+        assert_eq!(tags.genre.as_deref(), Some("Rock"));
+        assert_eq!((tags.track, tags.year), (Some(3), Some(1999)));
+        assert_eq!(tags.duration, Some(Duration::from_millis(215_500)));
+        assert_eq!((tags.track_gain, tags.album_gain), (Some(-6.5), Some(-7.25)));
+        assert_eq!((tags.track_peak, tags.album_peak), (Some(0.98), Some(1.0)));
+        assert_eq!(tracks[1].tags.title.as_deref(), Some("cached"));
+        assert!(tracks[1].tags.artist.is_none() && tracks[1].tags.duration.is_none());
+    }
+
+    #[test]
+    fn parse_starts_empty_for_another_version_or_root() {
+        let mut library = Library { root: PathBuf::from("/music"), tracks: BTreeMap::new() };
+        let track = cached(PathBuf::from("/music/a.mp3"), Duration::ZERO, 0);
+        library.tracks.insert(track.path.clone(), track);
+        let text = library.to_text().unwrap();
+
+        assert_eq!(Library::parse(Path::new("/music"), &text).tracks().count(), 1);
+        assert_eq!(Library::parse(Path::new("/other"), &text).tracks().count(), 0);
+        let old = text.replacen(&format!("version={}", FORMAT_VERSION), "version=2", 1);
+        assert_eq!(Library::parse(Path::new("/music"), &old).tracks().count(), 0);
+        assert_eq!(Library::parse(Path::new("/music"), "").tracks().count(), 0);
+    }
+
+    #[test]
+    fn scan_rereads_only_new_and_changed_files() {
+        let root = temp_root("scan");
+        let [same, touched, resized, added, removed] =
+            ["same.mp3", "touched.mp3", "album/resized.mp3", "album/added.mp3", "removed.mp3"].map(|name| root.join(name));
+        for path in [&same, &touched, &resized, &added] {
+            fs::write(path, b"data").unwrap();
+        }
+        fs::write(root.join("cover.jpg"), b"data").unwrap();
+
+        let mut library = Library { root: root.clone(), tracks: BTreeMap::new() };
+        let previous = [
+            cached(same.clone(), modified(&same), 4),
+            cached(touched.clone(), modified(&touched) - Duration::from_secs(1), 4),
+            cached(resized.clone(), modified(&resized), 3),
+            cached(removed.clone(), Duration::ZERO, 4),
+        ];
+        for track in previous {
+            library.tracks.insert(track.path.clone(), track);
+        }
+
+        library.scan(is_track);
+        let titles: Vec<(PathBuf, Option<String>)> =
+            library.tracks().map(|t| (t.path.clone(), t.tags.title.clone())).collect();
+        assert_eq!(
+            titles,
+            [
+                (added.clone(), None),
+                (resized.clone(), None),
+                (same.clone(), Some("cached".to_string())),
+                (touched.clone(), None),
+            ]
+        );
+        // 読み直した曲には今の更新時刻と大きさが入る
+        let touched = library.tracks().find(|t| t.path == touched).unwrap();
+        assert_eq!((touched.modified, touched.size), (modified(&touched.path), 4));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
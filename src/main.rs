@@ -1,26 +1,79 @@
 // src/main.rs
 
+mod browse;
+mod cli;
+mod config;
+mod dsp;
+mod eq;
+mod format;
+mod keymap;
+mod library;
+mod mp4;
+mod opus;
+mod output;
+mod persist;
+mod playback;
+mod playlist;
+mod queue;
+mod search;
+mod tags;
+mod visualizer;
+
 use std::{
-    io::{self, stdout,BufReader},
-    path::PathBuf,
-    fs
+    collections::VecDeque,
+    io::stdout,
+    path::{Path, PathBuf},
+    fs,
+    thread::{self, JoinHandle},
+    time::Duration,
 };
-use std::fs::File;
 
 use anyhow::Result;
 use crossterm::{
     terminal::{enable_raw_mode, disable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
     execute,
-    event::{self, Event, KeyCode}, // KeyCode を use
+    event::{self, Event, KeyCode, KeyEvent}, // KeyCode を use
 };
 use ratatui::{
     prelude::*,
     widgets::{Block, Borders, List, ListItem, ListState}, // 必要なものを整理
     style::{Style, Modifier}, // Modifier を use
 };
-use rodio::{Decoder, OutputStream, Sink};
-use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rodio::{Sink, Source};
+
+use browse::{Entry, Group, View};
+use cli::Command;
+use config::Config;
+use eq::EqPanel;
+use format::FormatCache;
+use keymap::{Action, Context, KeyPress, Resolution};
+use library::Library;
+use output::{AudioOutput, OutputKind};
+use persist::SavedState;
+use playback::{PlaybackClock, PlaybackOptions, Reopener, ReplayGainMode};
+use queue::{Queue, RepeatMode};
+use search::{Prompt, PromptKind};
+use tags::TagCache;
+use visualizer::{Visualizer, VisualizerMode};
+
+// 左右キーと H/L キーで移動する秒数
+const SEEK_STEP_SECS: i64 = 5;
+const SEEK_LONG_STEP_SECS: i64 = 30;
+// +/- キー1回で変わる音量
+const VOLUME_STEP: f32 = 0.05;
+// 前の曲に戻るとき、これ以上進んでいたら代わりに曲の頭へ戻す
+const RESTART_THRESHOLD: Duration = Duration::from_secs(3);
+// 覚えておく再生履歴の最大数
+const HISTORY_LIMIT: usize = 100;
+// 曲の残りがこれを切ったら次の曲を Sink に積んでおき、曲間の無音をなくす
+const GAPLESS_PRELOAD: Duration = Duration::from_secs(5);
+// クロスフェードの長さの既定値と、( ) キーで変えられる範囲
+const DEFAULT_CROSSFADE: Duration = Duration::from_secs(5);
+const MAX_CROSSFADE_SECS: u64 = 12;
+// [ ] キー1回で変わる再生速度と、その範囲
+const SPEED_STEP: f32 = 0.1;
+const MIN_SPEED: f32 = 0.5;
+const MAX_SPEED: f32 = 3.0;
 
 #[derive(PartialEq)]
 enum AppState {
@@ -28,43 +81,138 @@ enum AppState {
     Playing,
     Paused,
 }
+
+// 曲間なし再生のために、再生中の曲の後ろへ先に積んでおいた曲
+struct Preloaded {
+    path: PathBuf,
+    // 開けなかった場合は None。曲が終わったら通常どおり次の曲へ進む
+    clock: Option<PlaybackClock>,
+}
+
+// キー操作の対象になっているペイン
+#[derive(PartialEq)]
+enum Focus {
+    Browser,
+    Queue,
+}
 // アプリケーションの状態を管理する構造体
 struct App {
     current_path: String,
-    files: Vec<PathBuf>,
+    // ディレクトリ (タグ表示ではその階層) の項目すべてと、そのうち絞り込みに一致して表示している項目
+    // list_state は表示している files の位置を指す
+    entries: Vec<Entry>,
+    files: Vec<Entry>,
     list_state: ListState,
-    _stream: OutputStream,
+    view: View,
+    // タグ表示で開いているまとまり。上の階層から順に並ぶ
+    groups: Vec<Group>,
+    // 空でなければ、これにあいまいに一致する項目だけを表示する
+    filter: String,
+    // フッターで検索語や絞り込みを入力中ならその状態
+    prompt: Option<Prompt>,
+    // n/N で使う、最後に確定した検索語
+    search: Option<String>,
+    queue: Queue,
+    queue_state: ListState,
+    focus: Focus,
+    formats: FormatCache,
+    tags: TagCache,
+    // 音楽フォルダ全体の曲の一覧と、裏で再スキャンしているならそのスレッド
+    library: Library,
+    library_scan: Option<JoinHandle<Library>>,
+    // true ならファイル名の代わりにタグの "アーティスト – タイトル" を表示する
+    show_tags: bool,
+    output: Box<dyn AudioOutput>,
     sink: Sink,
+    // クロスフェード中に、フェードアウトしていく前の曲を鳴らしている Sink
+    fading_sink: Option<Sink>,
+    crossfade: Duration,
+    crossfade_enabled: bool,
+    options: PlaybackOptions,
+    // シークや設定の切り替えで曲を開き直す裏のスレッド
+    reopener: Reopener,
+    speed: f32,
+    eq_panel: EqPanel,
+    config: Config,
+    // "gg" のような複数キーの割り当ての、押しかけのキー
+    pending_keys: Vec<KeyPress>,
+    show_help: bool,
+    help_scroll: u16,
+    visualizer: Visualizer,
     currently_playing: Option<PathBuf>,
+    clock: Option<PlaybackClock>,
+    preloaded: Option<Preloaded>,
+    // 再生し終えた(または飛ばした)曲のパス。末尾が直前の曲
+    history: VecDeque<PathBuf>,
     state: AppState,
-    is_shuffling: bool,
+    volume: f32,
+    is_muted: bool,
 }
 
 impl App {
     fn update_files(&mut self) -> Result<()> {
-        self.files = fs::read_dir(&self.current_path)?
+        self.entries = self.read_entries()?;
+        // 別のディレクトリを開いたら絞り込みは解除する
+        self.filter.clear();
+        self.files = self.entries.clone();
+        self.select_entry(None);
+        Ok(())
+    }
+
+    // 一覧を作り直す。絞り込みと選択中の項目はそのまま保つ
+    fn refresh_files(&mut self) {
+        match self.read_entries() {
+            Ok(entries) => {
+                self.entries = entries;
+                self.apply_filter();
+            }
+            Err(e) => eprintln!("Error reading directory: {:?}", e),
+        }
+    }
+
+    // 今の表示のしかたで一覧に並べる項目。フォルダ表示ではディレクトリを先に並べる
+    fn read_entries(&self) -> Result<Vec<Entry>> {
+        if self.view != View::Folders {
+            return Ok(browse::list(&self.library, self.view, &self.groups));
+        }
+        let mut paths: Vec<PathBuf> = fs::read_dir(&self.current_path)?
             .filter_map(Result::ok)
             .map(|e| e.path())
             .collect();
-        
-        if self.is_shuffling {
-            self.shuffle_files();
-        } else {
-            self.sort_files();
-        }
-        if !self.files.is_empty() {
-            self.list_state.select(Some(0));
-        }
-        Ok(())
+        paths.sort_by(|a,b| {
+            b.is_dir().cmp(&a.is_dir()).then_with(|| a.cmp(b))
+        });
+        Ok(paths.into_iter().map(Entry::Path).collect())
     }
 
     fn enter_directory(&mut self) {
         if let Some(selected_index) = self.list_state.selected() {
-            let selected_path = &self.files[selected_index].clone();
+            let selected_path = match &self.files[selected_index] {
+                Entry::Path(path) => &path.clone(),
+                // タグ表示のまとまりは、その中の一覧を開く
+                Entry::Group(group) => {
+                    self.groups.push(group.clone());
+                    self.update_files().expect("error");
+                    return;
+                }
+            };
             if selected_path.is_dir() {
                 self.current_path = selected_path.to_string_lossy().into_owned();
                 self.update_files().expect("error");
-            }else {
+            } else if playlist::is_playlist(selected_path) {
+                self.open_playlist(selected_path);
+            } else {
+                // このディレクトリの曲をキューに積み直し、選んだ曲から再生する
+                // 絞り込み中は表示している曲だけを積む
+                let tracks: Vec<PathBuf> = self.files.iter()
+                    .filter_map(Entry::path)
+                    .filter(|p| self.formats.get(p).is_some_and(|f| self.formats.accepts(f)))
+                    .cloned()
+                    .collect();
+                if let Some(start) = tracks.iter().position(|p| p == selected_path) {
+                    self.queue.replace(tracks, start);
+                    self.queue_state.select(Some(start));
+                }
                 if let Err(e) = self.play_music(selected_path){
                     eprintln!("Error playing music: {:?},path: {}", e, selected_path.
                         display());
@@ -73,30 +221,353 @@ impl App {
         }
     }
 
+    // プレイリストの曲でキューを置き換え、先頭から再生する
+    // 再生できる曲が1つも無ければ、今のキューはそのまま残す
+    fn open_playlist(&mut self, path: &Path) {
+        let tracks = self.load_playlist(path);
+        if tracks.is_empty() {
+            return;
+        }
+        let Some(first) = self.queue.replace(tracks, 0).cloned() else { return };
+        self.queue_state.select(Some(0));
+        if let Err(e) = self.play_music(&first) {
+            eprintln!("Error playing music: {:?},path: {}", e, first.display());
+        }
+    }
+
+    // プレイリストの曲のうち、再生できる形式のものだけを返す
+    fn load_playlist(&self, path: &Path) -> Vec<PathBuf> {
+        match playlist::load(path) {
+            Ok(tracks) => tracks.into_iter().filter(|p| self.formats.is_playable(p)).collect(),
+            Err(e) => {
+                eprintln!("Error reading playlist: {:?},path: {}", e, path.display());
+                Vec::new()
+            }
+        }
+    }
+
+    // キューを今のディレクトリに name.m3u8 として保存する
+    fn save_playlist(&mut self, name: &str) {
+        if name.is_empty() || self.queue.is_empty() {
+            return;
+        }
+        let dir = PathBuf::from(&self.current_path);
+        let path = if playlist::is_playlist(Path::new(name)) {
+            dir.join(name)
+        } else {
+            dir.join(format!("{}.m3u8", name))
+        };
+        if let Err(e) = playlist::save(&path, self.queue.tracks(), &self.tags) {
+            eprintln!("Error saving playlist: {:?},path: {}", e, path.display());
+            return;
+        }
+        // 保存したファイルが一覧に出るようにする
+        if self.view == View::Folders {
+            self.refresh_files();
+        }
+    }
+
+    // フォーカスのあるペインで選択中の項目を開く
+    fn open_selected(&mut self) {
+        match self.focus {
+            Focus::Browser => self.enter_directory(),
+            Focus::Queue => self.play_queue_selected(),
+        }
+    }
+
+    fn toggle_focus(&mut self) {
+        self.focus = match self.focus {
+            Focus::Browser => Focus::Queue,
+            Focus::Queue => Focus::Browser,
+        };
+    }
+
+    fn selected_entry(&self) -> Option<&Entry> {
+        self.list_state.selected().and_then(|i| self.files.get(i))
+    }
+
+    fn selected_file(&self) -> Option<&PathBuf> {
+        self.selected_entry().and_then(Entry::path)
+    }
+
+    // 選択中のファイルをキューの末尾に追加する。ディレクトリやタグ表示のまとまりなら中の曲をすべて追加する
+    fn enqueue_selected(&mut self) {
+        if let Some(Entry::Group(group)) = self.selected_entry() {
+            let mut groups = self.groups.clone();
+            groups.push(group.clone());
+            self.queue.extend(browse::tracks(&self.library, self.view, &groups));
+            self.clamp_queue_selection();
+            return;
+        }
+        let Some(path) = self.selected_file().cloned() else { return };
+        if path.is_dir() {
+            let tracks = queue::collect_tracks(&path, |p| self.formats.is_playable(p));
+            self.queue.extend(tracks);
+        } else if playlist::is_playlist(&path) {
+            let tracks = self.load_playlist(&path);
+            self.queue.extend(tracks);
+        } else if self.formats.is_playable(&path) {
+            self.queue.push(path);
+        }
+        self.clamp_queue_selection();
+    }
+
+    // タグ表示では、いま開いているまとまりの曲をすべて積む
+    fn enqueue_current_directory(&mut self) {
+        let tracks = if self.view == View::Folders {
+            let dir = PathBuf::from(&self.current_path);
+            queue::collect_tracks(&dir, |p| self.formats.is_playable(p))
+        } else {
+            browse::tracks(&self.library, self.view, &self.groups)
+        };
+        self.queue.extend(tracks);
+        self.clamp_queue_selection();
+    }
+
+    // 選択中の曲を、再生中の曲の次に割り込ませる
+    fn enqueue_next(&mut self) {
+        let Some(path) = self.selected_file().cloned() else { return };
+        if self.formats.is_playable(&path) {
+            self.queue.insert_next(path);
+            self.clamp_queue_selection();
+        }
+    }
+
+    fn play_queue_selected(&mut self) {
+        let Some(index) = self.queue_state.selected() else { return };
+        let Some(path) = self.queue.jump(index).cloned() else { return };
+        if let Err(e) = self.play_music(&path) {
+            eprintln!("Error playing music: {:?},path: {}", e, path.display());
+        }
+    }
+
+    fn remove_queue_selected(&mut self) {
+        if let Some(index) = self.queue_state.selected() {
+            self.queue.remove(index);
+            self.clamp_queue_selection();
+        }
+    }
+
+    fn move_queue_selected(&mut self, up: bool) {
+        if let Some(index) = self.queue_state.selected()
+            && let Some(target) = self.queue.move_track(index, up)
+        {
+            self.queue_state.select(Some(target));
+        }
+    }
+
+    fn clear_queue(&mut self) {
+        self.queue.clear();
+        self.queue_state.select(None);
+    }
+
+    // キューの長さが変わったあと、選択位置が範囲外にならないようにする
+    fn clamp_queue_selection(&mut self) {
+        let selected = match self.queue_state.selected() {
+            _ if self.queue.is_empty() => None,
+            Some(i) => Some(i.min(self.queue.len() - 1)),
+            None => Some(0),
+        };
+        self.queue_state.select(selected);
+    }
+
+    // 前回終了時の画面とキューを戻し、再生していた曲をその位置から再開する
+    fn restore_session(&mut self, saved: &SavedState) {
+        if let Some(dir) = &saved.current_path
+            && dir.is_dir()
+        {
+            self.current_path = dir.to_string_lossy().into_owned();
+            if let Err(e) = self.update_files() {
+                eprintln!("Error reading directory: {:?}", e);
+            }
+        }
+        if let Some(selected) = &saved.selected
+            && let Some(index) = self.files.iter().position(|e| e.path() == Some(selected))
+        {
+            self.list_state.select(Some(index));
+        }
+
+        self.queue.extend(saved.queue.iter().cloned());
+        if let Some(position) = saved.queue_position {
+            self.queue.jump(position);
+        }
+        self.queue_state.select(self.queue.position());
+        self.clamp_queue_selection();
+
+        let Some(path) = &saved.playing else { return };
+        if !path.is_file() || self.start_track(path).is_err() {
+            return;
+        }
+        if self.config.resume_paused {
+            self.pause_playback();
+        }
+        self.seek_to(saved.position);
+    }
+
+    fn save_session(&self, saved: &mut SavedState) {
+        saved.current_path = Some(PathBuf::from(&self.current_path));
+        saved.selected = self.selected_file().cloned();
+        saved.queue = self.queue.tracks().to_vec();
+        saved.queue_position = self.queue.position();
+        saved.playing = self.currently_playing.clone();
+        saved.position = self.position().unwrap_or_default();
+        saved.shuffle = self.queue.is_shuffled();
+    }
+
+    // 裏のスレッドでライブラリを再スキャンして保存する。スキャン中なら何もしない
+    fn start_library_scan(&mut self) {
+        if self.library_scan.is_some() {
+            return;
+        }
+        let mut library = self.library.clone();
+        let formats = FormatCache::new(self.config.formats.clone());
+        self.library_scan = Some(thread::spawn(move || {
+            library.scan(|p| formats.is_playable(p));
+            if let Err(e) = library.save() {
+                eprintln!("Error saving library: {:?}", e);
+            }
+            library
+        }));
+    }
+
+    // スキャンが終わっていれば結果を取り込む
+    fn update_library(&mut self) {
+        if !self.library_scan.as_ref().is_some_and(JoinHandle::is_finished) {
+            return;
+        }
+        let Some(scan) = self.library_scan.take() else { return };
+        match scan.join() {
+            Ok(library) => self.set_library(library),
+            Err(_) => eprintln!("Error scanning library"),
+        }
+    }
+
+    // ライブラリで読んだタグは、一覧の表示にもそのまま使う
+    fn set_library(&mut self, library: Library) {
+        for track in library.tracks() {
+            self.tags.insert(track.path.clone(), track.tags.clone());
+        }
+        self.library = library;
+        if self.view != View::Folders {
+            self.refresh_files();
+        }
+    }
+
+    // フォルダ → アーティスト → ジャンル → 年 の順に表示を切り替える。タグ表示は最上位の階層から始める
+    fn cycle_view(&mut self) {
+        self.view = self.view.next();
+        self.groups.clear();
+        if let Err(e) = self.update_files() {
+            eprintln!("Error reading directory: {:?}", e);
+        }
+    }
+
     fn leave_directory(&mut self){
+        // タグ表示では1つ上の階層に戻り、いま出てきたまとまりを選択する
+        if self.view != View::Folders {
+            if let Some(group) = self.groups.pop() {
+                self.update_files().unwrap_or_default();
+                self.select_entry(Some(&Entry::Group(group)));
+            }
+            return;
+        }
         if let Some(parent) = PathBuf::from(&self.current_path).parent() {
             self.current_path = parent.to_string_lossy().into_owned();
             self.update_files().unwrap_or_default();
         }
     }
 
-    fn play_music(&mut self, path: &PathBuf) -> Result<()> {
+    fn play_music(&mut self, path: &Path) -> Result<()> {
+        let previous = self.currently_playing.clone();
+        self.start_track(path)?;
+
+        if let Some(previous) = previous {
+            self.push_history(previous);
+        }
+        Ok(())
+    }
+
+    fn push_history(&mut self, path: PathBuf) {
+        self.history.push_back(path);
+        if self.history.len() > HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+    }
+
+    // 履歴に残さずに曲を再生する
+    fn start_track(&mut self, path: &Path) -> Result<()> {
         self.sink.stop();
+        self.preloaded = None;
+        self.fading_sink = None;
+        self.reopener.cancel();
 
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let source = Decoder::new(reader)?;
+        let (source, clock) = playback::open_track(path, &self.options)?;
         self.sink.append(source);
+        self.sink.play();
         
-        self.currently_playing = Some(path.clone());
+        self.currently_playing = Some(path.to_path_buf());
+        self.clock = Some(clock);
         self.state = AppState::Playing;
 
         Ok(())
     }
 
+    // 現在の曲を指定位置から開き直すよう裏のスレッドに頼む。開けたら update_reopen で差し替える
+    // 一時停止中ならそのまま一時停止を保つ
+    fn seek_to(&mut self, position: Duration) {
+        let Some(path) = &self.currently_playing else { return };
+        let Some(clock) = &self.clock else { return };
+        let position = match clock.total() {
+            Some(total) => position.min(total),
+            None => position,
+        };
+        self.reopener.request(path, position, clock, &self.options);
+    }
+
+    // 開き直しの結果が届いていれば、今の音源と入れ替える
+    fn update_reopen(&mut self) {
+        match self.reopener.poll() {
+            Some(Ok((source, clock))) => {
+                self.sink.stop();
+                self.preloaded = None;
+                self.fading_sink = None;
+                self.sink.append(source);
+                self.clock = Some(clock);
+            }
+            Some(Err(e)) => eprintln!("Error seeking: {:?}", e),
+            None => {}
+        }
+    }
+
+    // 曲の中の今の位置。シークの途中なら移動先の位置
+    fn position(&self) -> Option<Duration> {
+        self.reopener.pending().or_else(|| self.clock.as_ref().map(|c| c.elapsed()))
+    }
+
+    // 続けて押したときは、まだ終わっていないシークの移動先から数える
+    fn seek_relative(&mut self, secs: i64) {
+        let Some(elapsed) = self.position() else { return };
+        let delta = Duration::from_secs(secs.unsigned_abs());
+        let target = if secs < 0 {
+            elapsed.saturating_sub(delta)
+        } else {
+            elapsed + delta
+        };
+        self.seek_to(target);
+    }
+
+    // 曲全体の percent % の位置へ移動する (長さが分からない曲では何もしない)
+    fn seek_percent(&mut self, percent: u32) {
+        let Some(total) = self.clock.as_ref().and_then(|c| c.total()) else { return };
+        self.seek_to(total * percent / 100);
+    }
+
     fn pause_playback(&mut self) {
         if self.state == AppState::Playing {
             self.sink.pause();
+            if let Some(fading) = &self.fading_sink {
+                fading.pause();
+            }
             self.state = AppState::Paused;
         }
     }
@@ -104,158 +575,919 @@ impl App {
     fn resume_playback(&mut self) {
         if self.state == AppState::Paused {
             self.sink.play();
+            if let Some(fading) = &self.fading_sink {
+                fading.play();
+            }
             self.state = AppState::Playing;
         }
     }
 
     fn stop_playback(&mut self) {
         self.sink.stop();
+        self.preloaded = None;
+        self.fading_sink = None;
         self.currently_playing = None;
+        self.clock = None;
+        self.reopener.cancel();
         self.state = AppState::Normal;
+        self.visualizer.tap.clear();
+    }
+
+    fn change_volume(&mut self, delta: f32) {
+        self.volume = (self.volume + delta).clamp(0.0, 1.0);
+        self.is_muted = false;
+        self.apply_volume();
+    }
+
+    fn toggle_mute(&mut self) {
+        self.is_muted = !self.is_muted;
+        self.apply_volume();
+    }
+
+    fn apply_volume(&self) {
+        let volume = if self.is_muted { 0.0 } else { self.volume };
+        self.sink.set_volume(volume);
+        if let Some(fading) = &self.fading_sink {
+            fading.set_volume(volume);
+        }
     }
 
     fn select_next(&mut self) {
-        if self.files.is_empty() { return; }
-        let i = match self.list_state.selected() {
-            Some(i) => if i >= self.files.len() - 1 { 0 } else { i + 1 },
+        let (len, state) = self.focused_list();
+        if len == 0 { return; }
+        let i = match state.selected() {
+            Some(i) => if i >= len - 1 { 0 } else { i + 1 },
             None => 0,
         };
-        self.list_state.select(Some(i));
+        state.select(Some(i));
     }
 
     fn select_previous(&mut self) {
-        if self.files.is_empty() { return; }
-        let i = match self.list_state.selected() {
-            Some(i) => if i == 0 { self.files.len() - 1 } else { i - 1 },
+        let (len, state) = self.focused_list();
+        if len == 0 { return; }
+        let i = match state.selected() {
+            Some(i) => if i == 0 { len - 1 } else { i - 1 },
             None => 0,
         };
-        self.list_state.select(Some(i));
+        state.select(Some(i));
     }
 
-    fn play_next_song(&mut self) {
-        if self.files.is_empty() {
-            self.stop_playback();
+    fn select_first(&mut self) {
+        let (len, state) = self.focused_list();
+        if len > 0 {
+            state.select(Some(0));
+        }
+    }
+
+    fn select_last(&mut self) {
+        let (len, state) = self.focused_list();
+        if len > 0 {
+            state.select(Some(len - 1));
+        }
+    }
+
+    fn focused_list(&mut self) -> (usize, &mut ListState) {
+        match self.focus {
+            Focus::Browser => (self.files.len(), &mut self.list_state),
+            Focus::Queue => (self.queue.len(), &mut self.queue_state),
+        }
+    }
+
+    // 毎フレーム呼ばれ、曲の切り替わりを準備する
+    fn update_transition(&mut self) {
+        self.update_reopen();
+        if self.fading_sink.as_ref().is_some_and(Sink::empty) {
+            self.fading_sink = None;
+        }
+        // 開き直しを待っている間に次の曲へ切り替えると、届いた結果で前の曲に戻ってしまう
+        if self.reopener.pending().is_some() {
             return;
         }
+        // 先読み済みの曲があれば、クロスフェードに切り替えられても先にそちらを済ませる
+        if self.crossfade_enabled && self.preloaded.is_none() {
+            self.update_crossfade();
+        } else {
+            self.update_gapless();
+        }
+    }
 
-        let current_index = self.currently_playing.as_ref()
-            .and_then(|p| self.files.iter().position(|f| f == p));
-        let start_index = current_index.map_or(0, |i| i + 1);
-        let next_song = self.files.iter().cycle().skip
-            (start_index).take(self.files.len())
-            .find(|path| path.is_file() &&
-                path.extension().map_or(false, |ext| ext == "mp3" || ext == "flac")
-            );
+    // 曲の残りがクロスフェードの長さを切ったら、次の曲を別の Sink でフェードインさせ、
+    // 今の曲は残りの時間でフェードアウトさせる
+    fn update_crossfade(&mut self) {
+        if self.state != AppState::Playing || self.fading_sink.is_some() {
+            return;
+        }
+        let Some(clock) = &self.clock else { return };
+        let Some(total) = clock.total() else { return };
+        let remaining = total.saturating_sub(clock.elapsed());
+        // 設定より短い曲が丸ごとフェードアウトにならないよう、重ねるのは曲の半分までにする
+        if remaining > self.crossfade.min(total / 2) {
+            return;
+        }
+        // 長さ 0 のフェードは音量の計算が壊れるので、最低限の長さは確保する
+        let remaining = remaining.max(Duration::from_millis(100));
+        // フェードアウトは時計と同じく伸縮前の長さで数えるが、フェードインは伸縮後の音に掛かる
+        // 音程を保って速度を変えているときは、伸縮後の長さに直して両方の長さを揃える
+        let fade_in = if self.options.preserve_pitch {
+            remaining.div_f32(self.speed)
+        } else {
+            remaining
+        };
+        let Some(path) = self.queue.peek_next().cloned() else { return };
+        let (source, next_clock) = match playback::open_track(&path, &self.options) {
+            Ok(opened) => opened,
+            Err(_) => {
+                // 開けなかった曲は先読み失敗として扱い、曲が終わってから通常どおり次へ進む
+                self.preloaded = Some(Preloaded { path, clock: None });
+                return;
+            }
+        };
+        let Ok(sink) = self.output.new_sink() else { return };
+        sink.set_volume(self.sink.volume());
+        sink.set_speed(self.sink.speed());
+        sink.append(source.fade_in(fade_in));
+        clock.fade_out(remaining);
+
+        self.fading_sink = Some(std::mem::replace(&mut self.sink, sink));
+        self.queue.advance(false);
+        if let Some(previous) = self.currently_playing.replace(path) {
+            self.push_history(previous);
+        }
+        self.clock = Some(next_clock);
+    }
+
+    // 切り替えた設定をすぐに聴けるよう、再生中の曲を同じ位置から開き直す
+    fn cycle_replay_gain(&mut self) {
+        self.options.replay_gain = self.options.replay_gain.next();
+        if let Some(position) = self.position() {
+            self.seek_to(position);
+        }
+    }
 
-        if let Some(song_path) = next_song {
-            let _ = self.play_music(&song_path.clone());
+    fn change_speed(&mut self, delta: f32) {
+        // 0.1 刻みからずれないよう丸めておく
+        self.speed = ((self.speed + delta) * 10.0).round() / 10.0;
+        self.speed = self.speed.clamp(MIN_SPEED, MAX_SPEED);
+        self.apply_speed();
+    }
+
+    fn reset_speed(&mut self) {
+        self.speed = 1.0;
+        self.apply_speed();
+    }
+
+    // 音程を保つ場合は伸縮処理に速度を渡し、Sink 自体は等速にする
+    fn apply_speed(&self) {
+        let (sink_speed, stretch_speed) = if self.options.preserve_pitch {
+            (1.0, self.speed)
         } else {
-            self.stop_playback();
+            (self.speed, 1.0)
+        };
+        self.options.stretch_speed.set(stretch_speed);
+        self.sink.set_speed(sink_speed);
+        if let Some(fading) = &self.fading_sink {
+            fading.set_speed(sink_speed);
         }
     }
 
-    fn shuffle_files(&mut self) {
-        let mut rng = thread_rng();
-        let (mut dirs, mut files): (Vec<_>,Vec<_>) = self
-                .files.iter().cloned().partition(|p| p.is_dir());
-        files.shuffle(&mut rng);
-        dirs.sort();
-        dirs.append(&mut files);
-        self.files = dirs;
+    // 伸縮処理は曲を開くときに組み込むので、切り替えたら同じ位置から開き直す
+    fn toggle_preserve_pitch(&mut self) {
+        self.options.preserve_pitch = !self.options.preserve_pitch;
+        self.apply_speed();
+        if let Some(position) = self.position() {
+            self.seek_to(position);
+        }
     }
 
-    fn toggle_shuffle(&mut self) {
-        self.is_shuffling = !self.is_shuffling;
-        if self.is_shuffling {
-            self.shuffle_files();
+    fn cycle_visualizer(&mut self) {
+        self.visualizer.mode = self.visualizer.mode.next();
+    }
+
+    // キー入力を割り当てに従って処理する。false を返したらアプリを終了する
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        // プリセット名や検索語などの入力中は、文字キーもすべて入力として扱う
+        if self.eq_panel.naming.is_some() {
+            self.handle_preset_name_key(key.code);
+            return true;
+        }
+        if self.prompt.is_some() {
+            self.handle_prompt_key(key.code);
+            return true;
+        }
+        // イコライザ画面とヘルプを開いている間は、それぞれの画面の割り当てだけを使う
+        let context = if self.eq_panel.is_open {
+            Context::Equalizer
+        } else if self.show_help {
+            Context::Help
         } else {
-            self.sort_files();
+            Context::Main
+        };
+        self.pending_keys.push(KeyPress::from(key));
+        match self.config.keymap.resolve(context, &self.pending_keys) {
+            Resolution::Action(action) => {
+                self.pending_keys.clear();
+                self.perform(action)
+            }
+            Resolution::Pending => true,
+            Resolution::None => {
+                // 並びの途中で割り当ての無いキーが来たら、そのキーだけで押し直したものとして扱う
+                let retry = self.pending_keys.len() > 1;
+                self.pending_keys.clear();
+                if retry {
+                    return self.handle_key(key);
+                }
+                // ヘルプは割り当ての無いキーで閉じる
+                if context == Context::Help {
+                    self.show_help = false;
+                }
+                true
+            }
         }
-        self.list_state.select(Some(0));
     }
 
-    fn sort_files(&mut self) {
-        self.files.sort_by(|a,b| {
-            b.is_dir().cmp(&a.is_dir()).then_with(|| a.cmp(b))
-        });
+    fn perform(&mut self, action: Action) -> bool {
+        // 再生中の曲に対する操作は、何も再生していなければ無視する
+        let playing = self.state != AppState::Normal;
+        match action {
+            Action::Quit => return false,
+            Action::Up => self.select_previous(),
+            Action::Down => self.select_next(),
+            Action::First => self.select_first(),
+            Action::Last => self.select_last(),
+            Action::Open => self.open_selected(),
+            Action::Parent => self.leave_directory(),
+            Action::ToggleFocus => self.toggle_focus(),
+            Action::Search => self.open_prompt(PromptKind::Search),
+            Action::Filter => self.open_prompt(PromptKind::Filter),
+            Action::SearchNext => self.search_next(true),
+            Action::SearchPrevious => self.search_next(false),
+            Action::RescanLibrary => self.start_library_scan(),
+            Action::CycleView => self.cycle_view(),
+            Action::TogglePause => match self.state {
+                AppState::Playing => self.pause_playback(),
+                AppState::Paused => self.resume_playback(),
+                AppState::Normal => {}
+            },
+            Action::Stop => self.stop_playback(),
+            Action::Next if playing => self.skip_to_next_song(),
+            Action::Previous if playing => self.play_previous_song(),
+            Action::SeekBack if playing => self.seek_relative(-SEEK_STEP_SECS),
+            Action::SeekForward if playing => self.seek_relative(SEEK_STEP_SECS),
+            Action::SeekBackLong if playing => self.seek_relative(-SEEK_LONG_STEP_SECS),
+            Action::SeekForwardLong if playing => self.seek_relative(SEEK_LONG_STEP_SECS),
+            Action::SeekPercent(tenths) if playing => self.seek_percent(tenths as u32 * 10),
+            Action::Next
+            | Action::Previous
+            | Action::SeekBack
+            | Action::SeekForward
+            | Action::SeekBackLong
+            | Action::SeekForwardLong
+            | Action::SeekPercent(_) => {}
+            Action::VolumeUp => self.change_volume(VOLUME_STEP),
+            Action::VolumeDown => self.change_volume(-VOLUME_STEP),
+            Action::Mute => self.toggle_mute(),
+            Action::ToggleShuffle => self.toggle_shuffle(),
+            Action::CycleRepeat => self.cycle_repeat(),
+            Action::Enqueue => self.enqueue_selected(),
+            Action::EnqueueDir => self.enqueue_current_directory(),
+            Action::PlayNext => self.enqueue_next(),
+            Action::Remove => self.remove_queue_selected(),
+            Action::MoveUp => self.move_queue_selected(true),
+            Action::MoveDown => self.move_queue_selected(false),
+            Action::ClearQueue => self.clear_queue(),
+            Action::SavePlaylist => self.open_prompt(PromptKind::SavePlaylist),
+            Action::ToggleTags => self.toggle_tag_display(),
+            Action::CycleReplayGain => self.cycle_replay_gain(),
+            Action::SpeedDown => self.change_speed(-SPEED_STEP),
+            Action::SpeedUp => self.change_speed(SPEED_STEP),
+            Action::SpeedReset => self.reset_speed(),
+            Action::TogglePreservePitch => self.toggle_preserve_pitch(),
+            Action::ToggleCrossfade => self.toggle_crossfade(),
+            Action::CrossfadeShorter => self.change_crossfade(-1),
+            Action::CrossfadeLonger => self.change_crossfade(1),
+            Action::ToggleEqualizer => self.toggle_eq_panel(),
+            Action::CycleVisualizer => self.cycle_visualizer(),
+            Action::ToggleHelp => {
+                self.show_help = true;
+                self.help_scroll = 0;
+            }
+            Action::EqNextBand => self.move_eq_band(1),
+            Action::EqPreviousBand => self.move_eq_band(-1),
+            Action::EqGainDown => self.change_eq_band(-eq::GAIN_STEP),
+            Action::EqGainUp => self.change_eq_band(eq::GAIN_STEP),
+            Action::EqResetBand => self.change_eq_band(-self.options.eq.gains()[self.eq_panel.band]),
+            Action::EqNextPreset => self.next_eq_preset(),
+            Action::EqSavePreset => self.eq_panel.naming = Some(String::new()),
+            Action::EqDeletePreset => self.delete_eq_preset(),
+            Action::EqClose => self.eq_panel.is_open = false,
+            Action::HelpScrollDown => self.scroll_help(1),
+            Action::HelpScrollUp => self.scroll_help(-1),
+        }
+        true
+    }
+
+    fn scroll_help(&mut self, delta: i16) {
+        // 分類ごとに見出しと空行が1行ずつ付く (最後の空行は無い)
+        let lines: usize = self.config.keymap.help().iter().map(|(_, rows)| rows.len() + 2).sum::<usize>() - 1;
+        // 画面に収まる行数は render_help と同じく、上下の余白と枠を除いた分
+        let visible = crossterm::terminal::size().map_or(0, |(_, height)| height.saturating_sub(4));
+        let max_scroll = (lines as u16).saturating_sub(visible);
+        self.help_scroll = self.help_scroll.saturating_add_signed(delta).min(max_scroll);
+    }
+
+    // フッターで入力を始める。検索と絞り込みはファイルブラウザが対象
+    fn open_prompt(&mut self, kind: PromptKind) {
+        if kind != PromptKind::SavePlaylist {
+            self.focus = Focus::Browser;
+        }
+        let text = match kind {
+            PromptKind::Search | PromptKind::SavePlaylist => String::new(),
+            // 絞り込みは今の文字列の続きから編集する
+            PromptKind::Filter => self.filter.clone(),
+        };
+        self.prompt = Some(Prompt { kind, text, origin: self.selected_entry().cloned() });
+    }
+
+    // Enter で確定し、Esc で入力を始める前の選択に戻す (絞り込みは解除する)
+    fn handle_prompt_key(&mut self, code: KeyCode) {
+        let Some(prompt) = &mut self.prompt else { return };
+        match code {
+            KeyCode::Char(c) => prompt.text.push(c),
+            KeyCode::Backspace => {
+                prompt.text.pop();
+            }
+            KeyCode::Enter => {
+                let (kind, text) = (prompt.kind, prompt.text.trim().to_string());
+                self.prompt = None;
+                match kind {
+                    PromptKind::Search if !text.is_empty() => self.search = Some(text),
+                    PromptKind::SavePlaylist => self.save_playlist(&text),
+                    PromptKind::Search | PromptKind::Filter => {}
+                }
+                return;
+            }
+            KeyCode::Esc => {
+                let origin = prompt.origin.clone();
+                if prompt.kind == PromptKind::Filter {
+                    self.filter.clear();
+                    self.apply_filter();
+                }
+                self.prompt = None;
+                self.select_entry(origin.as_ref());
+                return;
+            }
+            _ => return,
+        }
+        self.update_prompt();
     }
 
+    // 入力が変わるたびに一覧へ反映する
+    fn update_prompt(&mut self) {
+        let Some(prompt) = &self.prompt else { return };
+        let (kind, text, origin) = (prompt.kind, prompt.text.clone(), prompt.origin.clone());
+        match kind {
+            // 入力を始めた位置から探し直すので、文字を消すと手前の一致に戻る
+            PromptKind::Search => {
+                self.select_entry(origin.as_ref());
+                let from = self.list_state.selected().unwrap_or(0);
+                if !text.is_empty()
+                    && let Some(index) = self.find_match(&text, from, true)
+                {
+                    self.list_state.select(Some(index));
+                }
+            }
+            PromptKind::Filter => {
+                self.filter = text;
+                self.apply_filter();
+                // 元の項目がまた表示されたら、そちらを選び直す
+                if let Some(origin) = &origin
+                    && let Some(index) = self.files.iter().position(|e| e == origin)
+                {
+                    self.list_state.select(Some(index));
+                }
+            }
+            // 名前は Enter で確定するまで使わない
+            PromptKind::SavePlaylist => {}
+        }
+    }
+
+    // 最後の検索語に一致する次(または前)の項目へ移る。末尾まで来たら先頭から探す
+    fn search_next(&mut self, forward: bool) {
+        let Some(pattern) = self.search.clone() else { return };
+        let len = self.files.len();
+        if len == 0 {
+            return;
+        }
+        self.focus = Focus::Browser;
+        let current = self.list_state.selected().unwrap_or(0);
+        let from = if forward { (current + 1) % len } else { (current + len - 1) % len };
+        if let Some(index) = self.find_match(&pattern, from, forward) {
+            self.list_state.select(Some(index));
+        }
+    }
+
+    // from の位置から順に(逆向きなら手前へ)一周して、pattern に一致する項目を探す
+    fn find_match(&self, pattern: &str, from: usize, forward: bool) -> Option<usize> {
+        let len = self.files.len();
+        (0..len)
+            .map(|i| if forward { (from + i) % len } else { (from + len - i) % len })
+            .find(|&i| search::matches(pattern, &self.list_name(&self.files[i])))
+    }
+
+    // 絞り込みの文字列に一致する項目だけを表示し直す。選択中の項目が残っていれば選択を保つ
+    fn apply_filter(&mut self) {
+        let selected = self.selected_entry().cloned();
+        let files: Vec<Entry> = self.entries
+            .iter()
+            .filter(|e| search::fuzzy_matches(&self.filter, &self.list_name(e)))
+            .cloned()
+            .collect();
+        self.files = files;
+        self.select_entry(selected.as_ref());
+    }
+
+    // 表示中の一覧から entry を選択する。見つからなければ先頭を選ぶ
+    fn select_entry(&mut self, entry: Option<&Entry>) {
+        let index = entry.and_then(|entry| self.files.iter().position(|e| e == entry));
+        let selected = match index {
+            Some(i) => Some(i),
+            None if self.files.is_empty() => None,
+            None => Some(0),
+        };
+        self.list_state.select(selected);
+    }
+
+    fn toggle_eq_panel(&mut self) {
+        self.eq_panel.is_open = !self.eq_panel.is_open;
+    }
+
+    // プリセット名の入力中のキー
+    fn handle_preset_name_key(&mut self, code: KeyCode) {
+        if let Some(name) = &mut self.eq_panel.naming {
+            match code {
+                KeyCode::Char(c) => name.push(c),
+                KeyCode::Backspace => {
+                    name.pop();
+                }
+                KeyCode::Enter => {
+                    let name = name.trim().to_string();
+                    self.eq_panel.naming = None;
+                    if !name.is_empty()
+                        && let Err(e) = self.eq_panel.save_preset(name, self.options.eq.gains())
+                    {
+                        eprintln!("Error saving preset: {:?}", e);
+                    }
+                }
+                KeyCode::Esc => self.eq_panel.naming = None,
+                _ => {}
+            }
+        }
+    }
+
+    // 選択するバンドを delta だけ動かす (端で反対側へ回る)
+    fn move_eq_band(&mut self, delta: isize) {
+        let bands = dsp::EQ_FREQUENCIES.len() as isize;
+        self.eq_panel.band = (self.eq_panel.band as isize + delta).rem_euclid(bands) as usize;
+    }
+
+    fn next_eq_preset(&mut self) {
+        let gains = self.eq_panel.next_preset();
+        self.options.eq.set_gains(gains);
+    }
+
+    fn delete_eq_preset(&mut self) {
+        if let Err(e) = self.eq_panel.delete_preset() {
+            eprintln!("Error deleting preset: {:?}", e);
+        }
+    }
+
+    fn change_eq_band(&mut self, delta: f32) {
+        let mut gains = self.options.eq.gains();
+        let band = self.eq_panel.band;
+        gains[band] = (gains[band] + delta).clamp(-eq::MAX_GAIN, eq::MAX_GAIN);
+        self.options.eq.set_gains(gains);
+        self.eq_panel.preset = None;
+    }
+
+    fn toggle_crossfade(&mut self) {
+        self.crossfade_enabled = !self.crossfade_enabled;
+    }
+
+    fn change_crossfade(&mut self, secs: i64) {
+        let secs = (self.crossfade.as_secs() as i64 + secs).clamp(1, MAX_CROSSFADE_SECS as i64);
+        self.crossfade = Duration::from_secs(secs as u64);
+    }
+
+    // 曲の終わりが近づいたら次の曲を先読みして Sink に積み、
+    // 先読みした曲が鳴り始めたら再生中の曲をそちらに切り替える
+    fn update_gapless(&mut self) {
+        if self.state == AppState::Normal {
+            return;
+        }
+
+        if let Some(preloaded) = &self.preloaded {
+            if preloaded.clock.as_ref().is_some_and(PlaybackClock::has_started) {
+                let Some(Preloaded { path, clock }) = self.preloaded.take() else { return };
+                // 先読みしたあとにキューが変わっていても、実際に鳴っている曲に合わせる
+                if self.queue.peek_next() == Some(&path) {
+                    self.queue.advance(false);
+                } else {
+                    self.queue.jump_to_path(&path);
+                }
+                if let Some(previous) = self.currently_playing.replace(path) {
+                    self.push_history(previous);
+                }
+                self.clock = clock;
+            }
+            return;
+        }
+
+        let Some(clock) = &self.clock else { return };
+        let Some(total) = clock.total() else { return };
+        if total.saturating_sub(clock.elapsed()) > GAPLESS_PRELOAD {
+            return;
+        }
+        let Some(path) = self.queue.peek_next().cloned() else { return };
+        let clock = match playback::open_track(&path, &self.options) {
+            Ok((source, clock)) => {
+                self.sink.append(source);
+                Some(clock)
+            }
+            Err(_) => None,
+        };
+        self.preloaded = Some(Preloaded { path, clock });
+    }
+
+    // 曲が終わったときに呼ばれ、リピートモードに従って次の曲を再生する
+    fn play_next_song(&mut self) {
+        self.advance_queue(false);
+    }
+
+    // 次の曲キーで呼ばれる。リピート1曲のときも次の曲へ進む
+    fn skip_to_next_song(&mut self) {
+        self.advance_queue(true);
+    }
+
+    fn advance_queue(&mut self, manual: bool) {
+        // 開けない曲は飛ばし、キューを一周しても再生できなければ止める
+        for _ in 0..self.queue.len() {
+            let Some(song_path) = self.queue.advance(manual).cloned() else { break };
+            if self.play_music(&song_path).is_ok() {
+                return;
+            }
+        }
+        self.stop_playback();
+    }
+
+    fn cycle_repeat(&mut self) {
+        self.queue.cycle_repeat();
+    }
+
+    // 曲の頭から少し進んでいれば頭に戻り、そうでなければ履歴をさかのぼって直前の曲を再生する
+    fn play_previous_song(&mut self) {
+        let elapsed = self.position().unwrap_or_default();
+        if elapsed <= RESTART_THRESHOLD
+            && let Some(path) = self.history.pop_back()
+        {
+            self.queue.jump_to_path(&path);
+            if let Err(e) = self.start_track(&path) {
+                eprintln!("Error playing music: {:?},path: {}", e, path.display());
+            }
+            return;
+        }
+        self.seek_to(Duration::ZERO);
+    }
+
+    fn toggle_tag_display(&mut self) {
+        self.show_tags = !self.show_tags;
+        // 表示名が変わるので、絞り込みもやり直す
+        if !self.filter.is_empty() {
+            self.apply_filter();
+        }
+    }
+
+    // 一覧に表示する名前。タグ表示が有効でタイトルがあればそれを使い、無ければファイル名
+    fn display_name(&self, path: &Path) -> String {
+        let tag_name = if self.show_tags {
+            self.tags.get(path).and_then(|t| t.display_name())
+        } else {
+            None
+        };
+        tag_name.unwrap_or_else(|| path.file_name().unwrap_or_default().to_string_lossy().into_owned())
+    }
+
+    // ファイルブラウザに表示する名前。検索や絞り込みもこの名前で行う
+    fn list_name(&self, entry: &Entry) -> String {
+        match entry {
+            Entry::Group(group) => group.label().to_string(),
+            Entry::Path(path) if path.is_dir() => {
+                path.file_name().unwrap_or_default().to_string_lossy().into_owned()
+            }
+            Entry::Path(path) if self.view != View::Folders => self.track_name(path),
+            Entry::Path(path) => self.display_name(path),
+        }
+    }
+
+    // タグ表示でアルバムの中に並べる名前。"03. タイトル" の形にする
+    fn track_name(&self, path: &Path) -> String {
+        let tags = self.tags.get(path);
+        let title = tags
+            .and_then(|t| t.title.clone())
+            .unwrap_or_else(|| path.file_name().unwrap_or_default().to_string_lossy().into_owned());
+        match tags.and_then(|t| t.track) {
+            Some(track) => format!("{:02}. {}", track, title),
+            None => title,
+        }
+    }
+
+    // 再生順だけをシャッフルする。ブラウザやキューの並びはそのまま
+    fn toggle_shuffle(&mut self) {
+        self.queue.toggle_shuffle();
+    }
+
+
         
 }
 
+// 出力先を開く。音声デバイスが無ければ、起動できるよう無音の出力に切り替える
+fn open_output(kind: &OutputKind) -> Result<Box<dyn AudioOutput>> {
+    match (kind.open(), kind) {
+        (Err(e), OutputKind::Device) => {
+            eprintln!("No audio device ({:?}); falling back to the null output", e);
+            OutputKind::Null.open()
+        }
+        (result, _) => result,
+    }
+}
+
 fn main() -> Result<()> {
-    // --- 1. ターミナルのセットアップ ---
-    enable_raw_mode()?;
-    let mut stdout = stdout();
-    execute!(stdout, EnterAlternateScreen)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
-    
-    let music_dir = dirs::audio_dir()
-        .unwrap_or_else(|| {
-            dirs::home_dir().expect("Coule not find home directory").join("Music")
-        });
-    fs::create_dir_all(&music_dir)?;
+    let args = match cli::parse(std::env::args().skip(1))? {
+        Command::Run(args) => args,
+        Command::Help => {
+            print!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Command::Version => {
+            println!("music_cli {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+    };
+    // 端末を切り替える前に開き、引数や設定ファイルのエラーはそのまま表示する
+    let config = Config::load()?;
+    let output = open_output(&args.output)?;
+
+    // ライブラリは引数で何を開いたかに関わらず、音楽フォルダ全体を対象にする
+    let library_root = config.music_dir.clone().unwrap_or_else(|| {
+        dirs::audio_dir()
+            .unwrap_or_else(|| {
+                dirs::home_dir().expect("Coule not find home directory").join("Music")
+            })
+    });
+
+    // 引数でファイルを指定されたら、そのディレクトリを開いてその曲を再生する
+    let (music_dir, start_file) = match &args.path {
+        Some(path) if path.is_dir() => (fs::canonicalize(path)?, None),
+        Some(path) if path.is_file() => {
+            let path = fs::canonicalize(path)?;
+            (path.parent().unwrap_or(Path::new("/")).to_path_buf(), Some(path))
+        }
+        Some(path) => anyhow::bail!("{}: no such file or directory", path.display()),
+        None if config.music_dir.is_some() => {
+            if !library_root.is_dir() {
+                anyhow::bail!("music_dir {} is not a directory", library_root.display());
+            }
+            (library_root.clone(), None)
+        }
+        None => {
+            fs::create_dir_all(&library_root)?;
+            (library_root.clone(), None)
+        }
+    };
 
-    let (_stream,stream_handle) = OutputStream::try_default()?;
-    let sink = Sink::try_new(&stream_handle)?;
+    let sink = output.new_sink()?;
+    let mut saved = SavedState::load();
+    let options = PlaybackOptions::default();
 
     let mut app = App {
         current_path: music_dir.to_string_lossy().into_owned(),
+        entries: Vec::new(),
         files: Vec::new(),
         list_state: ListState::default(),
-        _stream,
+        view: View::Folders,
+        groups: Vec::new(),
+        filter: String::new(),
+        prompt: None,
+        search: None,
+        queue: Queue::default(),
+        queue_state: ListState::default(),
+        focus: Focus::Browser,
+        formats: FormatCache::new(config.formats.clone()),
+        tags: TagCache::default(),
+        library: Library::default(),
+        library_scan: None,
+        show_tags: false,
+        output,
         sink,
+        fading_sink: None,
+        crossfade: DEFAULT_CROSSFADE,
+        crossfade_enabled: false,
+        visualizer: Visualizer::new(options.tap.clone()),
+        options,
+        reopener: Reopener::default(),
+        speed: 1.0,
+        eq_panel: EqPanel::new(config.eq_presets.clone()),
         currently_playing: None,
+        clock: None,
+        preloaded: None,
+        history: VecDeque::new(),
         state:AppState::Normal,
-        is_shuffling: false,
+        // 引数、設定ファイル、前回終了時の順に優先する
+        volume: args.volume.or(config.volume).unwrap_or(saved.volume),
+        is_muted: false,
+        config,
+        pending_keys: Vec::new(),
+        show_help: false,
+        help_scroll: 0,
     };
+    app.queue.set_repeat(args.repeat.unwrap_or(app.config.repeat));
+    app.apply_volume();
     app.update_files()?;
 
-       
+    // 開くものを指定されていなければ、前回終了時の続きから始める
+    let restore = args.path.is_none() && !args.no_ui && app.config.restore_session;
+    if restore {
+        app.restore_session(&saved);
+    }
+    // 戻したキューの並びと再生位置を元にシャッフルの順番を決める
+    if args.shuffle || app.config.shuffle || (restore && saved.shuffle) {
+        app.queue.toggle_shuffle();
+    }
+
+    if let Some(file) = &start_file {
+        app.formats.load(app.files.iter().filter_map(Entry::path));
+        app.list_state.select(app.files.iter().position(|e| e.path() == Some(file)));
+        app.enter_directory();
+    }
+
+    // 前回の一覧をすぐに使えるようにしてから、変わったファイルを裏で読み直す
+    if !args.no_ui {
+        app.set_library(Library::load(&library_root));
+        app.start_library_scan();
+    }
+
+    let res = if args.no_ui {
+        // ディレクトリだけ指定されたときは、その下の曲をすべて再生する
+        if start_file.is_none() {
+            app.enqueue_current_directory();
+            app.play_next_song();
+        }
+        run_headless(&mut app)
+    } else {
+        // --- 1. ターミナルのセットアップ ---
+        enable_raw_mode()?;
+        let mut stdout = stdout();
+        execute!(stdout, EnterAlternateScreen)?;
+        let backend = CrosstermBackend::new(stdout);
+        let mut terminal = Terminal::new(backend)?;
+
+        // --- 3. アプリケーションの実行 ---
+        let res = run_app(&mut terminal, &mut app);
 
-    // --- 3. アプリケーションの実行 ---
-    let res = run_app(&mut terminal, app); // appの所有権を渡す
+        // --- 4. ターミナルのクリーンアップ ---
+        disable_raw_mode()?;
+        execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+        res
+    };
 
-    // --- 4. ターミナルのクリーンアップ ---
-    disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    // 次回起動時のために音量と、画面で操作していたならそのセッションを保存しておく
+    saved.volume = app.volume;
+    if !args.no_ui {
+        app.save_session(&mut saved);
+    }
+    if let Err(e) = saved.save() {
+        eprintln!("Error saving state: {:?}", e);
+    }
     res
 }
+
+// 画面を出さずにキューを最後まで再生する (--no-ui)。曲が変わるたびに標準出力へ書く
+fn run_headless(app: &mut App) -> Result<()> {
+    let mut announced: Option<PathBuf> = None;
+    loop {
+        app.update_transition();
+        if app.state == AppState::Playing && app.sink.empty() {
+            app.play_next_song();
+        }
+        if app.currently_playing.is_none() {
+            return Ok(());
+        }
+        if announced != app.currently_playing {
+            announced = app.currently_playing.clone();
+            if let Some(path) = &announced {
+                app.tags.load([path]);
+                println!("Playing: {}", app.display_name(path));
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+}
+
 // アプリケーションのメインループ
-fn run_app(terminal: &mut Terminal<impl Backend>, mut app: App) -> Result<()> {
+fn run_app(terminal: &mut Terminal<impl Backend>, app: &mut App) -> Result<()> {
     loop {
+        app.update_library();
+        app.update_transition();
         if app.state == AppState::Playing && app.sink.empty() {
             app.play_next_song();
         }
+        // 表示するファイルの形式とタグを調べておく (調べ済みのものはキャッシュから使う)
+        // 絞り込みはタグの名前でも行うので、隠れている項目の分も調べる
+        let paths = app.entries.iter().filter_map(Entry::path);
+        app.formats.load(paths.clone());
+        app.tags.load(paths.chain(app.queue.tracks()).chain(&app.currently_playing));
+
         terminal.draw(|frame| {
             let chunks = ratatui::layout::Layout::default()
                 .direction(ratatui::layout::Direction::Vertical)
                 .constraints([
                     ratatui::layout::Constraint::Min(0),
                     ratatui::layout::Constraint::Length(1),
+                    ratatui::layout::Constraint::Length(1),
                 ])
                 .split(frame.size());
-            let main_area = chunks[0];
-            let footer_area = chunks[1];
+            let progress_area = chunks[1];
+            let footer_area = chunks[2];
 
-            let block = Block::default()
-                .title(app.current_path.as_str())
+            // 左にファイルブラウザ、右にキューを並べる
+            let panes = ratatui::layout::Layout::default()
+                .direction(ratatui::layout::Direction::Horizontal)
+                .constraints([
+                    ratatui::layout::Constraint::Percentage(60),
+                    ratatui::layout::Constraint::Percentage(40),
+                ])
+                .split(chunks[0]);
+            let main_area = panes[0];
+            // 右側はキューの下にビジュアライザと再生中の曲の情報を置く
+            let visualizer_height = if app.visualizer.mode == VisualizerMode::Off { 0 } else { 10 };
+            let side = ratatui::layout::Layout::default()
+                .direction(ratatui::layout::Direction::Vertical)
+                .constraints([
+                    ratatui::layout::Constraint::Min(0),
+                    ratatui::layout::Constraint::Length(visualizer_height),
+                    ratatui::layout::Constraint::Length(8),
+                ])
+                .split(panes[1]);
+            let queue_area = side[0];
+            let visualizer_area = side[1];
+            let info_area = side[2];
+
+            let theme = &app.config.theme;
+            let focused_style = Style::default().fg(theme.accent);
+            // タグ表示では開いているまとまりをたどった道筋を出す
+            let location = if app.view == View::Folders {
+                app.current_path.clone()
+            } else {
+                let names: Vec<&str> = std::iter::once(app.view.name())
+                    .chain(app.groups.iter().map(Group::label))
+                    .collect();
+                names.join(" / ")
+            };
+            // 絞り込み中は、その文字列と表示している件数をタイトルに出す
+            let title = if app.filter.is_empty() {
+                location
+            } else {
+                format!("{} [{}: {}/{}]", location, app.filter, app.files.len(), app.entries.len())
+            };
+            let mut block = Block::default()
+                .title(title)
                 .borders(Borders::ALL); // メソッドチェーンの途中にセミコロンは不要
+            if app.focus == Focus::Browser {
+                block = block.border_style(focused_style);
+            }
             
             let inner_area = block.inner(main_area);
             frame.render_widget(block, main_area);
 
             let items: Vec<ListItem> = app.files
                 .iter()
-                .map(|path| {
-                    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+                .map(|entry| {
+                    let file_name = app.list_name(entry);
+                    let Some(path) = entry.path() else {
+                        return ListItem::new(format!("📁 {}", file_name)).style(Style::default().fg(theme.directory));
+                    };
 
                     // 1. ファイル種別に応じて、アイコンと基本スタイルを決める
                     let (icon, base_style) = if path.is_dir() {
-                        ("📁", Style::default().fg(Color::Cyan))
-                    } else if path.extension().map_or(false, |ext| ext == "mp3" || ext == "flac") {
-                        ("🎵", Style::default())
+                        ("📁", Style::default().fg(theme.directory))
+                    } else if let Some(format) = app.formats.get(path) {
+                        // 形式は分かるが再生できないものは暗く表示する
+                        if app.formats.accepts(format) {
+                            ("🎵", Style::default())
+                        } else {
+                            ("🎵", Style::default().fg(theme.dimmed))
+                        }
+                    } else if playlist::is_playlist(path) {
+                        ("📃", Style::default())
                     } else {
                         ("📄", Style::default())
                     };
@@ -265,7 +1497,7 @@ fn run_app(terminal: &mut Terminal<impl Backend>, mut app: App) -> Result<()> {
 
                     // 2. もし再生中の曲なら、スタイルを上書きする
                     if app.currently_playing.as_ref() == Some(path) {
-                        item = item.style(Style::default().fg(Color::Green).add_modifier(Modifier::BOLD));
+                        item = item.style(Style::default().fg(theme.playing).add_modifier(Modifier::BOLD));
                     }
                     
                     item
@@ -278,19 +1510,135 @@ fn run_app(terminal: &mut Terminal<impl Backend>, mut app: App) -> Result<()> {
                 .highlight_symbol("> ");
 
             frame.render_stateful_widget(list, inner_area, &mut app.list_state);
+
+            let queue_items: Vec<ListItem> = app.queue.tracks()
+                .iter()
+                .enumerate()
+                .map(|(i, path)| {
+                    let file_name = app.display_name(path);
+                    let mut item = ListItem::new(format!("{:>3}. {}", i + 1, file_name));
+                    if app.queue.position() == Some(i) && app.currently_playing.as_ref() == Some(path) {
+                        item = item.style(Style::default().fg(theme.playing).add_modifier(Modifier::BOLD));
+                    }
+                    item
+                })
+                .collect();
+            let mut queue_block = Block::default()
+                .title(format!("Queue ({})", app.queue.len()))
+                .borders(Borders::ALL);
+            if app.focus == Focus::Queue {
+                queue_block = queue_block.border_style(focused_style);
+            }
+            let queue_list = List::new(queue_items)
+                .block(queue_block)
+                .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+                .highlight_symbol("> ");
+            frame.render_stateful_widget(queue_list, queue_area, &mut app.queue_state);
+
+            // 再生中の曲のタグ情報
+            let mut info_lines = Vec::new();
+            if let Some(path) = &app.currently_playing {
+                let tags = app.tags.get(path).cloned().unwrap_or_default();
+                let file_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                let duration = app.clock.as_ref().and_then(|c| c.total()).or(tags.duration);
+                let fields = [
+                    ("Title", Some(tags.title.unwrap_or(file_name))),
+                    ("Artist", tags.artist),
+                    ("Album", tags.album),
+                    ("Track", tags.track.map(|n| n.to_string())),
+                    ("Year", tags.year.map(|n| n.to_string())),
+                    ("Length", duration.map(format_duration)),
+                ];
+                for (label, value) in fields {
+                    info_lines.push(ratatui::text::Line::from(vec![
+                        ratatui::text::Span::styled(format!("{:<7}", label), Style::default().fg(theme.label)),
+                        ratatui::text::Span::raw(value.unwrap_or_else(|| "-".to_string())),
+                    ]));
+                }
+            }
+            let info_widget = ratatui::widgets::Paragraph::new(info_lines)
+                .block(Block::default().title("Now Playing").borders(Borders::ALL));
+            frame.render_widget(info_widget, info_area);
+
+            if app.visualizer.mode != VisualizerMode::Off {
+                render_visualizer(frame, &mut app.visualizer, visualizer_area);
+            }
             let mode_str = match app.state {
                 AppState::Normal => "NORMAL",
                 AppState::Playing => "PLAYING",
                 AppState::Paused => "PAUSED",
             };
-            let shuffle_str = if app.is_shuffling { "SHUFFLE" } else { "" };
+            let shuffle_str = if app.queue.is_shuffled() { "SHUFFLE" } else { "" };
+            let repeat_str = match app.queue.repeat() {
+                RepeatMode::Off => "",
+                RepeatMode::One => "REPEAT ONE",
+                RepeatMode::All => "REPEAT ALL",
+            };
+            let volume_str = if app.is_muted {
+                "MUTE".to_string()
+            } else {
+                format!("VOL {:>3}%", (app.volume * 100.0).round() as u32)
+            };
+
+            // 再生中の曲のタイトルと経過時間/総時間をゲージで表示する
+            if let (Some(path), Some(clock)) = (&app.currently_playing, &app.clock) {
+                let title = path.file_stem().unwrap_or_default().to_string_lossy();
+                let total_str = clock.total().map_or_else(|| "--:--".to_string(), format_duration);
+                let label = format!("{}  {} / {}", title, format_duration(clock.elapsed()), total_str);
+                let gauge = ratatui::widgets::Gauge::default()
+                    .gauge_style(Style::default().fg(theme.playing).bg(theme.dimmed))
+                    .ratio(clock.ratio().unwrap_or(0.0))
+                    .label(label);
+                frame.render_widget(gauge, progress_area);
+            }
+
+            let crossfade_str = if app.crossfade_enabled {
+                format!("XFADE {}s", app.crossfade.as_secs())
+            } else {
+                String::new()
+            };
+
+            let replay_gain_str = match app.options.replay_gain {
+                ReplayGainMode::Off => "",
+                ReplayGainMode::Track => "RG TRACK",
+                ReplayGainMode::Album => "RG ALBUM",
+            };
+
+            let speed_str = match (app.speed, app.options.preserve_pitch) {
+                (_, true) => format!("{:.1}x PITCH", app.speed),
+                (speed, false) if speed != 1.0 => format!("{:.1}x", speed),
+                _ => String::new(),
+            };
+
+            let library_str = if app.library_scan.is_some() { "SCANNING" } else { "" };
+
+            let eq_str = if app.eq_panel.preset == Some(0) {
+                String::new()
+            } else {
+                format!("EQ {}", app.eq_panel.preset_name())
+            };
 
             let footer_line = ratatui::text::Line::from(vec![
                 ratatui::text::Span::raw("-- "),
                 ratatui::text::Span::styled(mode_str, Style::default().add_modifier(Modifier::BOLD)),
                 ratatui::text::Span::raw(" --"),
+                ratatui::text::Span::raw(format!(" {}", keymap::format_sequence(&app.pending_keys))),
+                ratatui::text::Span::raw(" | "),
+                ratatui::text::Span::styled(shuffle_str, Style::default().fg(theme.accent)),
+                ratatui::text::Span::raw(" "),
+                ratatui::text::Span::styled(repeat_str, Style::default().fg(theme.accent)),
+                ratatui::text::Span::raw(" "),
+                ratatui::text::Span::styled(crossfade_str, Style::default().fg(theme.accent)),
+                ratatui::text::Span::raw(" "),
+                ratatui::text::Span::styled(replay_gain_str, Style::default().fg(theme.accent)),
+                ratatui::text::Span::raw(" "),
+                ratatui::text::Span::styled(speed_str, Style::default().fg(theme.accent)),
+                ratatui::text::Span::raw(" "),
+                ratatui::text::Span::styled(eq_str, Style::default().fg(theme.accent)),
+                ratatui::text::Span::raw(" "),
+                ratatui::text::Span::styled(library_str, Style::default().fg(theme.accent)),
                 ratatui::text::Span::raw(" | "),
-                ratatui::text::Span::styled(shuffle_str, Style::default().fg(Color::Yellow)),
+                ratatui::text::Span::raw(volume_str),
                 ratatui::text::Span::raw(" "),
             ]);
 
@@ -298,42 +1646,208 @@ fn run_app(terminal: &mut Terminal<impl Backend>, mut app: App) -> Result<()> {
                 .alignment(ratatui::layout::Alignment::Right);
             
             frame.render_widget(footer_widget, footer_area);
-        })?;
 
-        if event::poll(std::time::Duration::from_millis(50))? {
-            if let Event::Key(key) = event::read()? {
-                // 最初に、状態に依存しないグローバルなキーを処理
-                match key.code {
-                    KeyCode::Char('q') => return Ok(()),
-                    KeyCode::Char('d') => app.toggle_shuffle(),
-                    KeyCode::Esc => {
-                        app.stop_playback();
-                        continue; // 他のキー処理はスキップ
-                    }
-                    KeyCode::Char('j') | KeyCode::Down => app.select_next(),
-                    KeyCode::Char('k') | KeyCode::Up => app.select_previous(),
-                    KeyCode::Char('h') => app.leave_directory(),
-                    // 上記以外の場合は、状態依存のキー処理に移る
-                    _ => {
-                        match app.state {
-                            AppState::Normal => match key.code {
-                                KeyCode::Char('l') | KeyCode::Enter => app.enter_directory(),
-                                _ => {}
-                            },
-                            AppState::Playing => match key.code {
-                                KeyCode::Char('s') => app.pause_playback(),
-                                KeyCode::Char('l') | KeyCode::Enter => app.enter_directory(),
-                                _ => {}
-                            },
-                            AppState::Paused => match key.code {
-                                KeyCode::Char('s') => app.resume_playback(),
-                                KeyCode::Char('l') | KeyCode::Enter => app.enter_directory(),
-                                _ => {}
-                            }
-                        }
-                    }
-                }
+            // 入力中はフッターの左側に入力欄を重ねる
+            if let Some(prompt) = &app.prompt {
+                let prompt_line = ratatui::text::Line::from(vec![
+                    ratatui::text::Span::styled(prompt.label(), Style::default().fg(theme.accent)),
+                    ratatui::text::Span::raw(format!("{}_", prompt.text)),
+                ]);
+                frame.render_widget(ratatui::widgets::Paragraph::new(prompt_line), footer_area);
             }
+
+            if app.eq_panel.is_open {
+                render_eq_panel(frame, app);
+            }
+            if app.show_help {
+                render_help(frame, app);
+            }
+        })?;
+
+        if event::poll(app.config.poll_interval)?
+            && let Event::Key(key) = event::read()?
+            && !app.handle_key(key)
+        {
+            return Ok(());
         }
     } 
 }
+
+// スペクトラムは1列1本の棒で、オシロスコープは点字の点で直近の波形を描く
+fn render_visualizer(frame: &mut Frame, visualizer: &mut Visualizer, area: Rect) {
+    // 棒の高さを 1/8 文字単位で表す文字
+    const LEVELS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+    let block = Block::default().borders(Borders::ALL);
+    let inner = block.inner(area);
+    match visualizer.mode {
+        VisualizerMode::Spectrum => {
+            let height = inner.height as usize;
+            let bars = visualizer.spectrum(inner.width as usize);
+            let lines: Vec<ratatui::text::Line> = (0..height)
+                .map(|row| {
+                    // 下から数えた行の位置で色を変える
+                    let from_bottom = height - 1 - row;
+                    let color = if from_bottom * 3 >= height * 2 {
+                        Color::Red
+                    } else if from_bottom * 3 >= height {
+                        Color::Yellow
+                    } else {
+                        Color::Green
+                    };
+                    let text: String = bars
+                        .iter()
+                        .map(|bar| {
+                            let level = (bar * (height * 8) as f32) as usize;
+                            LEVELS[level.saturating_sub(from_bottom * 8).min(8)]
+                        })
+                        .collect();
+                    ratatui::text::Line::styled(text, Style::default().fg(color))
+                })
+                .collect();
+            let widget = ratatui::widgets::Paragraph::new(lines).block(block.title("Spectrum"));
+            frame.render_widget(widget, area);
+        }
+        VisualizerMode::Scope => {
+            let waveform = visualizer.waveform();
+            // 横幅の点の数に合わせて、直近の波形を間引かずにそのまま並べる
+            let len = (inner.width as usize * 2).min(waveform.len());
+            let coords: Vec<(f64, f64)> = waveform[waveform.len() - len..]
+                .iter()
+                .enumerate()
+                .map(|(i, s)| (i as f64, s.clamp(-1.0, 1.0) as f64))
+                .collect();
+            let canvas = ratatui::widgets::canvas::Canvas::default()
+                .block(block.title("Oscilloscope"))
+                .marker(ratatui::symbols::Marker::Braille)
+                .x_bounds([0.0, (inner.width as usize * 2) as f64])
+                .y_bounds([-1.0, 1.0])
+                .paint(|ctx| {
+                    ctx.draw(&ratatui::widgets::canvas::Points { coords: &coords, color: Color::Green });
+                });
+            frame.render_widget(canvas, area);
+        }
+        VisualizerMode::Off => {}
+    }
+}
+
+// 今のキー割り当てを分類ごとに一覧表示する
+fn render_help(frame: &mut Frame, app: &App) {
+    let help = app.config.keymap.help();
+    // キーの列の幅は、いちばん長い割り当てに合わせる
+    let rows = help.iter().flat_map(|(_, rows)| rows);
+    let key_width = rows.clone().map(|(keys, _)| keys.chars().count()).max().unwrap_or(0);
+    let description_width = rows.map(|(_, d)| d.chars().count()).max().unwrap_or(0);
+
+    let mut lines = Vec::new();
+    for (category, rows) in help {
+        if !lines.is_empty() {
+            lines.push(ratatui::text::Line::from(""));
+        }
+        lines.push(ratatui::text::Line::styled(
+            category,
+            Style::default().fg(app.config.theme.accent).add_modifier(Modifier::BOLD),
+        ));
+        for (keys, description) in rows {
+            lines.push(ratatui::text::Line::from(vec![
+                ratatui::text::Span::styled(
+                    format!("  {:<width$}  ", keys, width = key_width),
+                    Style::default().fg(app.config.theme.label),
+                ),
+                ratatui::text::Span::raw(description),
+            ]));
+        }
+    }
+
+    let height = (lines.len() as u16 + 2).min(frame.size().height.saturating_sub(2));
+    let width = (key_width + description_width + 6) as u16;
+    let area = centered_rect(width.max(44), height, frame.size());
+    let help = ratatui::widgets::Paragraph::new(lines)
+        .scroll((app.help_scroll, 0))
+        .block(
+            Block::default()
+                .title(format!(
+                    "Help ({}/{}: scroll, any other key: close)",
+                    app.config.keymap.first_key(Action::HelpScrollDown),
+                    app.config.keymap.first_key(Action::HelpScrollUp),
+                ))
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(app.config.theme.accent)),
+        );
+    frame.render_widget(ratatui::widgets::Clear, area);
+    frame.render_widget(help, area);
+}
+
+// 画面中央にイコライザのバンドとプリセットを表示する
+fn render_eq_panel(frame: &mut Frame, app: &App) {
+    // ゲインの位置を示す横棒の幅 (中央が 0 dB)
+    const BAR_WIDTH: usize = 25;
+
+    // 操作の案内は今のキー割り当てから作る
+    let key = |action| app.config.keymap.first_key(action);
+    let hint = format!(
+        "{}/{} band  {}/{} gain  {} reset  {} preset  {} save  {} delete",
+        key(Action::EqNextBand),
+        key(Action::EqPreviousBand),
+        key(Action::EqGainDown),
+        key(Action::EqGainUp),
+        key(Action::EqResetBand),
+        key(Action::EqNextPreset),
+        key(Action::EqSavePreset),
+        key(Action::EqDeletePreset),
+    );
+    // 割り当てによって案内の長さが変わるので、枠は案内が収まる幅にする
+    let area = centered_rect((hint.chars().count() as u16 + 2).max(52), 16, frame.size());
+    let gains = app.options.eq.gains();
+    let mut lines = vec![
+        ratatui::text::Line::from(format!("Preset: {}", app.eq_panel.preset_name())),
+        ratatui::text::Line::from(""),
+    ];
+    for (i, (freq, gain)) in dsp::EQ_FREQUENCIES.iter().zip(gains).enumerate() {
+        let label = if *freq >= 1000.0 {
+            format!("{:>3}k", freq / 1000.0)
+        } else {
+            format!("{:>4}", freq)
+        };
+        let knob = (((gain + eq::MAX_GAIN) / (2.0 * eq::MAX_GAIN)) * (BAR_WIDTH - 1) as f32).round() as usize;
+        let bar: String = (0..BAR_WIDTH).map(|j| if j == knob { '●' } else { '─' }).collect();
+        let style = if i == app.eq_panel.band {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default()
+        };
+        lines.push(ratatui::text::Line::styled(format!(" {} Hz {} {:+5.1} dB", label, bar, gain), style));
+    }
+    lines.push(ratatui::text::Line::from(""));
+    lines.push(match &app.eq_panel.naming {
+        Some(name) => ratatui::text::Line::from(format!("Save as: {}_", name)),
+        None => ratatui::text::Line::styled(hint, Style::default().fg(Color::DarkGray)),
+    });
+
+    let panel = ratatui::widgets::Paragraph::new(lines)
+        .block(Block::default().title("Equalizer").borders(Borders::ALL).border_style(Style::default().fg(app.config.theme.accent)));
+    frame.render_widget(ratatui::widgets::Clear, area);
+    frame.render_widget(panel, area);
+}
+
+// area の中央に置いた width x height の領域 (はみ出す場合は area に収める)
+fn centered_rect(width: u16, height: u16, area: Rect) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    }
+}
+
+// 再生位置を mm:ss (1時間以上なら h:mm:ss) の形にする
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
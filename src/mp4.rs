@@ -0,0 +1,110 @@
+// src/mp4.rs
+
+use std::{fs::File, path::Path, time::Duration};
+
+use anyhow::{anyhow, Result};
+use rodio::Source;
+use symphonia::core::{
+    audio::{SampleBuffer, SignalSpec},
+    codecs::{Decoder, DecoderOptions},
+    errors::Error,
+    formats::{FormatOptions, FormatReader},
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+// MP4 (AAC / ALAC) と ADTS の AAC のデコーダ
+// rodio 0.17 の Decoder は symphonia にファイルの長さを渡さないので、MP4 を開くと symphonia が
+// シークできないというエラーを返し、それが rodio の中で panic になる。File をそのまま渡せば長さが分かる
+pub struct Mp4Decoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    spec: SignalSpec,
+    total: Option<Duration>,
+    // 最後にデコードしたパケットのサンプル (チャンネルごとに交互に並ぶ) と、次に返す位置
+    buffer: SampleBuffer<i16>,
+    position: usize,
+}
+
+impl Mp4Decoder {
+    pub fn new(path: &Path) -> Result<Self> {
+        let stream = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+        let options = FormatOptions { enable_gapless: true, ..Default::default() };
+        let probed = symphonia::default::get_probe().format(&Hint::new(), stream, &options, &MetadataOptions::default())?;
+        let mut format = probed.format;
+        let track = format.default_track().ok_or_else(|| anyhow!("no audio track"))?;
+        let track_id = track.id;
+        let params = track.codec_params.clone();
+        let total = params.time_base.zip(params.n_frames).map(|(base, frames)| {
+            let time = base.calc_time(frames);
+            Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
+        });
+        let mut decoder = symphonia::default::get_codecs().make(&params, &DecoderOptions::default())?;
+        // チャンネル数はコンテナに書かれていないこともあるので、最初のパケットをデコードして決める
+        let (spec, buffer) =
+            decode_next(format.as_mut(), decoder.as_mut(), track_id).ok_or_else(|| anyhow!("no audio data"))?;
+        Ok(Mp4Decoder { format, decoder, track_id, spec, total, buffer, position: 0 })
+    }
+}
+
+// track_id の次のパケットをデコードする。壊れたパケットは読み飛ばし、最後まで読んだら None
+fn decode_next(
+    format: &mut dyn FormatReader,
+    decoder: &mut dyn Decoder,
+    track_id: u32,
+) -> Option<(SignalSpec, SampleBuffer<i16>)> {
+    loop {
+        let packet = format.next_packet().ok()?;
+        if packet.track_id() != track_id {
+            continue;
+        }
+        match decoder.decode(&packet) {
+            Ok(decoded) if decoded.frames() == 0 => continue,
+            Ok(decoded) => {
+                let spec = *decoded.spec();
+                let mut buffer = SampleBuffer::new(decoded.capacity() as u64, spec);
+                buffer.copy_interleaved_ref(decoded);
+                return Some((spec, buffer));
+            }
+            Err(Error::DecodeError(_)) => continue,
+            Err(_) => return None,
+        }
+    }
+}
+
+impl Iterator for Mp4Decoder {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = *self.buffer.samples().get(self.position)?;
+        self.position += 1;
+        // 次のパケットは先にデコードしておく。current_frame_len が曲の途中で 0 になると、rodio はそこで曲が終わったとみなす
+        if self.position == self.buffer.samples().len()
+            && let Some((spec, buffer)) = decode_next(self.format.as_mut(), self.decoder.as_mut(), self.track_id)
+        {
+            (self.spec, self.buffer, self.position) = (spec, buffer, 0);
+        }
+        Some(sample)
+    }
+}
+
+impl Source for Mp4Decoder {
+    // パケットごとにチャンネル数などが変わりうるので、今のパケットの残りを返す (0 なら曲の終わり)
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.buffer.samples().len() - self.position)
+    }
+
+    fn channels(&self) -> u16 {
+        self.spec.channels.count() as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.spec.rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total
+    }
+}
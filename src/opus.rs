@@ -0,0 +1,225 @@
+// src/opus.rs
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use ogg::PacketReader;
+use rodio::Source;
+use ropus::{Channels, DecodeMode, Decoder};
+
+use crate::tags;
+
+// Opus は常に 48kHz でデコードする
+const SAMPLE_RATE: u32 = 48_000;
+// 1パケットから出てくる最大のサンプル数 (120ms 分、チャンネルあたり)
+const MAX_FRAME: usize = 5_760;
+
+// Ogg Opus のデコーダ
+// rodio にも symphonia にも Opus のデコーダが無いので、Ogg のパケットを ropus に渡してデコードする
+pub struct OpusDecoder {
+    reader: PacketReader<BufReader<File>>,
+    decoder: Decoder,
+    serial: u32,
+    channels: u16,
+    total: Option<Duration>,
+    // 先頭でまだ捨てていない pre-skip のサンプル数 (チャンネルあたり)
+    skip: usize,
+    // これまでにデコードしたサンプル数 (チャンネルあたり、pre-skip を含む)。最後のページで余分を切り落とすのに使う
+    decoded: u64,
+    // 最後にデコードしたパケットのサンプル (チャンネルごとに交互に並ぶ) と、次に返す位置
+    buffer: Vec<i16>,
+    position: usize,
+}
+
+impl OpusDecoder {
+    pub fn new(path: &Path) -> Result<Self> {
+        let mut reader = PacketReader::new(BufReader::new(File::open(path)?));
+        let head = reader.read_packet_expected()?;
+        let serial = head.stream_serial();
+        let ident = &head.data;
+        if !ident.starts_with(b"OpusHead") || ident.len() < 19 {
+            bail!("not an Opus stream");
+        }
+        // チャンネルの割り当て方式 0 (モノラルかステレオ) だけに対応する
+        let channels = match (ident[9], ident[18]) {
+            (1, 0) => Channels::Mono,
+            (2, 0) => Channels::Stereo,
+            (count, _) => bail!("{}-channel Opus streams are not supported", count),
+        };
+        let pre_skip = u16::from_le_bytes([ident[10], ident[11]]) as usize;
+        let output_gain = i16::from_le_bytes([ident[16], ident[17]]);
+
+        let mut decoder = Decoder::new(SAMPLE_RATE, channels).map_err(|e| anyhow!("{}", e))?;
+        decoder.set_gain(output_gain as i32).map_err(|e| anyhow!("{}", e))?;
+
+        // 2つめのパケットはタグ (tags.rs で読む)
+        let comment = reader.read_packet_expected()?;
+        if !comment.data.starts_with(b"OpusTags") {
+            bail!("missing OpusTags header");
+        }
+
+        let total = File::open(path)
+            .ok()
+            .and_then(|mut file| tags::ogg_last_granule(&mut file, serial).ok().flatten())
+            .map(|granule| {
+                Duration::from_secs_f64(granule.saturating_sub(pre_skip as u64) as f64 / SAMPLE_RATE as f64)
+            });
+
+        let mut opus = OpusDecoder {
+            reader,
+            decoder,
+            serial,
+            channels: channels.count() as u16,
+            total,
+            skip: pre_skip,
+            decoded: 0,
+            buffer: Vec::new(),
+            position: 0,
+        };
+        opus.decode_next();
+        Ok(opus)
+    }
+
+    // 次の音声パケットをデコードして buffer に入れる。最後まで読んだら buffer は空のまま
+    fn decode_next(&mut self) {
+        let channels = self.channels as usize;
+        self.buffer.clear();
+        self.position = 0;
+        while self.buffer.is_empty() {
+            let Ok(Some(packet)) = self.reader.read_packet() else { return };
+            // 多重化された別のストリームのパケットは読み飛ばす
+            if packet.stream_serial() != self.serial {
+                continue;
+            }
+            self.buffer.resize(MAX_FRAME * channels, 0);
+            // 壊れたパケットは読み飛ばす
+            let mut frames =
+                self.decoder.decode(&packet.data, &mut self.buffer, DecodeMode::Normal).unwrap_or_default();
+            // 最後のページのグラニュール位置より後ろは、フレームを埋めるための余分なサンプル
+            if packet.last_in_stream() {
+                let end = packet.absgp_page().saturating_sub(self.decoded) as usize;
+                frames = frames.min(end);
+            }
+            self.decoded += frames as u64;
+            let skip = self.skip.min(frames);
+            self.skip -= skip;
+            self.buffer.truncate(frames * channels);
+            self.buffer.drain(..skip * channels);
+        }
+    }
+}
+
+impl Iterator for OpusDecoder {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = *self.buffer.get(self.position)?;
+        self.position += 1;
+        // 次のパケットは先にデコードしておく。current_frame_len が曲の途中で 0 になると、rodio はそこで曲が終わったとみなす
+        if self.position == self.buffer.len() {
+            self.decode_next();
+        }
+        Some(sample)
+    }
+}
+
+impl Source for OpusDecoder {
+    // 今のパケットの残り (0 なら曲の終わり)
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.buffer.len() - self.position)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ogg::{PacketWriteEndInfo, PacketWriter};
+    use ropus::{Application, Encoder};
+
+    // 440Hz のステレオの正弦波を Ogg Opus にして一時ファイルに書く
+    // グラニュール位置は pre-skip を含めて数え、最後のページで末尾の trim サンプルを切り落とす
+    fn sine_opus(name: &str, frames: usize, trim: u64) -> (std::path::PathBuf, u64) {
+        const FRAME: usize = 960;
+        let mut encoder = Encoder::builder(SAMPLE_RATE, Channels::Stereo, Application::Audio).build().unwrap();
+        let pre_skip = encoder.lookahead() as u16;
+
+        let mut head = b"OpusHead\x01\x02".to_vec();
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        let mut comment = b"OpusTags".to_vec();
+        comment.extend_from_slice(&[0; 8]);
+
+        let mut writer = PacketWriter::new(Vec::new());
+        writer.write_packet(head.into(), 1, PacketWriteEndInfo::EndPage, 0).unwrap();
+        writer.write_packet(comment.into(), 1, PacketWriteEndInfo::EndPage, 0).unwrap();
+        let mut packet = [0u8; 4000];
+        for i in 0..frames {
+            let pcm: Vec<i16> = (0..FRAME * 2)
+                .map(|n| {
+                    let t = (i * FRAME + n / 2) as f32 / SAMPLE_RATE as f32;
+                    ((t * 440.0 * std::f32::consts::TAU).sin() * 8_000.0) as i16
+                })
+                .collect();
+            let len = encoder.encode(&pcm, &mut packet).unwrap();
+            let (end, granule) = if i + 1 == frames {
+                (PacketWriteEndInfo::EndStream, ((i + 1) * FRAME) as u64 - trim)
+            } else {
+                (PacketWriteEndInfo::NormalPacket, ((i + 1) * FRAME) as u64)
+            };
+            writer.write_packet(packet[..len].into(), 1, end, granule).unwrap();
+        }
+
+        let dir = std::env::temp_dir().join(format!("music_cli_opus_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, writer.into_inner()).unwrap();
+        (path, pre_skip as u64)
+    }
+
+    #[test]
+    fn decodes_ogg_opus_without_pre_skip_and_end_padding() {
+        let (path, pre_skip) = sine_opus("sine.opus", 50, 100);
+        let length = 50 * 960 - 100 - pre_skip;
+        let decoder = OpusDecoder::new(&path).unwrap();
+        assert_eq!(decoder.channels(), 2);
+        assert_eq!(decoder.sample_rate(), 48_000);
+        assert_eq!(decoder.total_duration(), Some(Duration::from_secs_f64(length as f64 / 48_000.0)));
+
+        let samples: Vec<i16> = decoder.collect();
+        assert_eq!(samples.len() as u64, length * 2);
+        // 無音ではなく、元の振幅に近い音が出ている
+        let peak = samples[10_000..].iter().map(|s| s.unsigned_abs()).max().unwrap();
+        assert!((6_000..=10_000).contains(&peak), "{}", peak);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_stream_that_is_not_opus() {
+        let dir = std::env::temp_dir().join(format!("music_cli_opus_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("vorbis.opus");
+        let mut writer = PacketWriter::new(Vec::new());
+        writer.write_packet(b"\x01vorbis".to_vec().into(), 1, PacketWriteEndInfo::EndStream, 0).unwrap();
+        std::fs::write(&path, writer.into_inner()).unwrap();
+        assert!(OpusDecoder::new(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
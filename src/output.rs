@@ -0,0 +1,145 @@
+// src/output.rs
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use rodio::{dynamic_mixer::{self, DynamicMixerController}, OutputStream, OutputStreamHandle, Sink};
+
+// 音声デバイスを使わない出力で、内部で混ぜるときの形式
+const CHANNELS: u16 = 2;
+const SAMPLE_RATE: u32 = 44_100;
+// 一度に混ぜて消費する長さ
+const CHUNK: Duration = Duration::from_millis(10);
+
+// 音の出し先。クロスフェードでは2つの Sink を同時に鳴らすので、何本でも作れるようにする
+pub trait AudioOutput {
+    fn new_sink(&self) -> Result<Sink>;
+}
+
+// どの出力を使うか (--output で選ぶ)
+pub enum OutputKind {
+    Device,
+    Null,
+    Wav(PathBuf),
+}
+
+impl OutputKind {
+    // "device" / "null" / "wav:ファイル名" を読む
+    pub fn parse(spec: &str) -> Result<Self> {
+        match spec {
+            "device" => Ok(OutputKind::Device),
+            "null" => Ok(OutputKind::Null),
+            _ => match spec.strip_prefix("wav:") {
+                Some(path) if !path.is_empty() => Ok(OutputKind::Wav(PathBuf::from(path))),
+                _ => bail!("unknown output '{}' (expected device, null or wav:FILE)", spec),
+            },
+        }
+    }
+
+    pub fn open(&self) -> Result<Box<dyn AudioOutput>> {
+        Ok(match self {
+            OutputKind::Device => Box::new(DeviceOutput::open()?),
+            OutputKind::Null => Box::new(MixerOutput::spawn(|_| Ok(()))),
+            OutputKind::Wav(path) => {
+                let spec = hound::WavSpec {
+                    channels: CHANNELS,
+                    sample_rate: SAMPLE_RATE,
+                    bits_per_sample: 16,
+                    sample_format: hound::SampleFormat::Int,
+                };
+                let mut writer = hound::WavWriter::create(path, spec)
+                    .with_context(|| format!("cannot create {}", path.display()))?;
+                Box::new(MixerOutput::spawn(move |samples| {
+                    for &sample in samples {
+                        writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+                    }
+                    Ok(())
+                }))
+            }
+        })
+    }
+}
+
+// 実際の音声デバイス
+pub struct DeviceOutput {
+    // ストリームを落とすと音が止まるので持っておく
+    _stream: OutputStream,
+    handle: OutputStreamHandle,
+}
+
+impl DeviceOutput {
+    fn open() -> Result<Self> {
+        let (_stream, handle) = OutputStream::try_default()?;
+        Ok(DeviceOutput { _stream, handle })
+    }
+}
+
+impl AudioOutput for DeviceOutput {
+    fn new_sink(&self) -> Result<Sink> {
+        Ok(Sink::try_new(&self.handle)?)
+    }
+}
+
+// デバイスの代わりに別スレッドで Sink の音を混ぜ、実時間と同じ速さで consume に渡す
+// 再生位置の時計や曲の切り替えは、デバイスで鳴らしたときと同じように進む
+pub struct MixerOutput {
+    controller: Arc<DynamicMixerController<f32>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MixerOutput {
+    fn spawn(mut consume: impl FnMut(&[f32]) -> hound::Result<()> + Send + 'static) -> Self {
+        let (controller, mut mixer) = dynamic_mixer::mixer::<f32>(CHANNELS, SAMPLE_RATE);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let chunk_len = (SAMPLE_RATE as u128 * CHUNK.as_millis() / 1000) as usize * CHANNELS as usize;
+                let mut chunk = Vec::with_capacity(chunk_len);
+                let start = Instant::now();
+                let mut elapsed = Duration::ZERO;
+                while !stop.load(Ordering::Relaxed) {
+                    // 鳴らす Sink が無いあいだは無音を出す
+                    chunk.clear();
+                    chunk.extend((0..chunk_len).map(|_| mixer.next().unwrap_or(0.0)));
+                    if let Err(e) = consume(&chunk) {
+                        eprintln!("Error writing audio: {:?}", e);
+                        return;
+                    }
+                    elapsed += CHUNK;
+                    if let Some(wait) = elapsed.checked_sub(start.elapsed()) {
+                        thread::sleep(wait);
+                    }
+                }
+            })
+        };
+        MixerOutput { controller, stop, thread: Some(thread) }
+    }
+}
+
+impl AudioOutput for MixerOutput {
+    fn new_sink(&self) -> Result<Sink> {
+        let (sink, output) = Sink::new_idle();
+        self.controller.add(output);
+        Ok(sink)
+    }
+}
+
+impl Drop for MixerOutput {
+    // スレッドを止めてから終わる (WAV はここで書き手が落ちてヘッダが確定する)
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,167 @@
+// src/persist.rs
+
+use std::{fmt::Write, fs, path::PathBuf, time::Duration};
+
+use anyhow::Result;
+
+// 実行をまたいで覚えておく値
+// ファイルは "key=value" を1行ずつ並べただけの形式で、知らないキーは読み飛ばす
+pub struct SavedState {
+    pub volume: f32,
+    // 以下は前回終了時のセッション。次の起動で同じ画面と曲から続ける
+    pub current_path: Option<PathBuf>,
+    // ファイルブラウザで選択していた項目
+    pub selected: Option<PathBuf>,
+    pub queue: Vec<PathBuf>,
+    pub queue_position: Option<usize>,
+    pub playing: Option<PathBuf>,
+    pub position: Duration,
+    pub shuffle: bool,
+}
+
+impl Default for SavedState {
+    fn default() -> Self {
+        SavedState {
+            volume: 1.0,
+            current_path: None,
+            selected: None,
+            queue: Vec::new(),
+            queue_position: None,
+            playing: None,
+            position: Duration::ZERO,
+            shuffle: false,
+        }
+    }
+}
+
+impl SavedState {
+    // 保存先が無い・壊れている場合は既定値を返す
+    pub fn load() -> Self {
+        match state_file().and_then(|p| fs::read_to_string(p).ok()) {
+            Some(content) => SavedState::parse(&content),
+            None => SavedState::default(),
+        }
+    }
+
+    fn parse(content: &str) -> Self {
+        let mut state = SavedState::default();
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once('=') else { continue };
+            // パスは前後の空白も名前の一部なので trim しない
+            match key.trim() {
+                "volume" => {
+                    // NaN は clamp しても NaN のままなので除く
+                    if let Ok(v) = value.trim().parse::<f32>()
+                        && !v.is_nan()
+                    {
+                        state.volume = v.clamp(0.0, 1.0);
+                    }
+                }
+                "current_path" => state.current_path = Some(PathBuf::from(value)),
+                "selected" => state.selected = Some(PathBuf::from(value)),
+                "queue" => state.queue.push(PathBuf::from(value)),
+                "queue_position" => state.queue_position = value.trim().parse().ok(),
+                "playing" => state.playing = Some(PathBuf::from(value)),
+                "position" => {
+                    if let Ok(secs) = value.trim().parse::<f64>()
+                        && secs.is_finite()
+                        && secs >= 0.0
+                    {
+                        state.position = Duration::from_secs_f64(secs);
+                    }
+                }
+                "shuffle" => state.shuffle = value.trim() == "true",
+                _ => {}
+            }
+        }
+        state
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = state_file() else { return Ok(()) };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_text()?)?;
+        Ok(())
+    }
+
+    fn to_text(&self) -> Result<String> {
+        let mut content = format!("volume={}\n", self.volume);
+        if let Some(current_path) = &self.current_path {
+            writeln!(content, "current_path={}", current_path.display())?;
+        }
+        if let Some(selected) = &self.selected {
+            writeln!(content, "selected={}", selected.display())?;
+        }
+        for track in &self.queue {
+            writeln!(content, "queue={}", track.display())?;
+        }
+        if let Some(position) = self.queue_position {
+            writeln!(content, "queue_position={}", position)?;
+        }
+        if let Some(playing) = &self.playing {
+            writeln!(content, "playing={}", playing.display())?;
+            writeln!(content, "position={}", self.position.as_secs_f64())?;
+        }
+        writeln!(content, "shuffle={}", self.shuffle)?;
+        Ok(content)
+    }
+}
+
+// Linux では $XDG_STATE_HOME/music_cli/state、それ以外ではローカルのデータディレクトリに置く
+fn state_file() -> Option<PathBuf> {
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .map(|dir| dir.join("music_cli").join("state"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_text_then_parse_round_trips() {
+        let state = SavedState {
+            volume: 0.25,
+            current_path: Some(PathBuf::from("/music/ trailing and leading ")),
+            selected: Some(PathBuf::from("/music/ trailing and leading /a=b.mp3")),
+            queue: vec![PathBuf::from("/music/one.mp3"), PathBuf::from(" two.mp3 "), PathBuf::from("/music/one.mp3")],
+            queue_position: Some(2),
+            playing: Some(PathBuf::from("/music/one.mp3")),
+            position: Duration::from_millis(83_250),
+            shuffle: true,
+        };
+        let loaded = SavedState::parse(&state.to_text().unwrap());
+        assert_eq!(loaded.volume, 0.25);
+        assert_eq!(loaded.current_path, state.current_path);
+        assert_eq!(loaded.selected, state.selected);
+        assert_eq!(loaded.queue, state.queue);
+        assert_eq!(loaded.queue_position, Some(2));
+        assert_eq!(loaded.playing, state.playing);
+        assert_eq!(loaded.position, Duration::from_millis(83_250));
+        assert!(loaded.shuffle);
+    }
+
+    #[test]
+    fn parse_keeps_queue_lines_in_order_and_skips_unknown_lines() {
+        let state = SavedState::parse("queue=/b.mp3\nfuture=1\nnot a setting\nqueue=/a.mp3\n\nqueue= /c.mp3\n");
+        assert_eq!(state.queue, [PathBuf::from("/b.mp3"), PathBuf::from("/a.mp3"), PathBuf::from(" /c.mp3")]);
+        assert_eq!(state.volume, 1.0);
+        assert!(state.playing.is_none() && !state.shuffle);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_numbers() {
+        for position in ["-1", "NaN", "inf", "1e400", "abc"] {
+            let state = SavedState::parse(&format!("playing=/a.mp3\nposition={}\n", position));
+            assert_eq!(state.position, Duration::ZERO, "{}", position);
+        }
+        assert_eq!(SavedState::parse("position= 1.5 \n").position, Duration::from_millis(1_500));
+        assert_eq!(SavedState::parse("volume=3\n").volume, 1.0);
+        assert_eq!(SavedState::parse("volume=-1\n").volume, 0.0);
+        assert_eq!(SavedState::parse("volume=loud\n").volume, 1.0);
+        assert_eq!(SavedState::parse("volume=NaN\n").volume, 1.0);
+        assert_eq!(SavedState::parse("queue_position=-1\n").queue_position, None);
+    }
+}
@@ -0,0 +1,467 @@
+// src/playback.rs
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use rodio::{Decoder, Sample, Source};
+
+use crate::{
+    dsp::{Equalizer, Gain, SharedEq, SharedSpeed, TimeStretch},
+    format::{self, AudioFormat},
+    mp4::Mp4Decoder,
+    opus::OpusDecoder,
+    tags,
+    visualizer::{SampleTap, Tap},
+};
+
+// 音源と時計で共有する状態
+struct TrackState {
+    // Sink に引き出されたサンプル数
+    samples: AtomicU64,
+    // フェードアウトを始めたサンプル位置 (NO_FADE なら未開始) と、無音になるまでのサンプル数
+    fade_start: AtomicU64,
+    fade_len: AtomicU64,
+}
+
+const NO_FADE: u64 = u64::MAX;
+
+// 再生中の曲の経過時間を知るための時計
+// 実際に Sink に引き出されたサンプル数から計算するので、一時停止中は進まない
+#[derive(Clone)]
+pub struct PlaybackClock {
+    offset: Duration,
+    state: Arc<TrackState>,
+    sample_rate: u32,
+    channels: u16,
+    // 曲の長さ。デコーダが知らない場合は別スレッドでファイルを走査して後から埋める
+    total: Arc<OnceLock<Duration>>,
+}
+
+impl PlaybackClock {
+    pub fn elapsed(&self) -> Duration {
+        let per_second = self.sample_rate as u64 * self.channels as u64;
+        if per_second == 0 {
+            return self.offset;
+        }
+        let played = self.state.samples.load(Ordering::Relaxed);
+        self.offset + Duration::from_secs_f64(played as f64 / per_second as f64)
+    }
+
+    // 音源が Sink に引き出され始めたか (先読みした曲に切り替わったかの判定に使う)
+    pub fn has_started(&self) -> bool {
+        self.state.samples.load(Ordering::Relaxed) > 0
+    }
+
+    // 今の位置から duration かけて音量を下げ、無音になったところで音源を終わらせる
+    pub fn fade_out(&self, duration: Duration) {
+        let per_second = self.sample_rate as f64 * self.channels as f64;
+        let len = (duration.as_secs_f64() * per_second) as u64;
+        self.state.fade_len.store(len.max(1), Ordering::Relaxed);
+        self.state.fade_start.store(self.state.samples.load(Ordering::Relaxed), Ordering::Release);
+    }
+
+    pub fn total(&self) -> Option<Duration> {
+        self.total.get().copied()
+    }
+
+    // 0.0〜1.0 の進捗率 (長さが分からなければ None)
+    pub fn ratio(&self) -> Option<f64> {
+        let total = self.total()?.as_secs_f64();
+        if total <= 0.0 {
+            return None;
+        }
+        Some((self.elapsed().as_secs_f64() / total).clamp(0.0, 1.0))
+    }
+}
+
+// 引き出されたサンプル数を数え、フェードアウトを指示されたら音量を下げていくラッパー
+pub struct Tracked<S> {
+    inner: S,
+    state: Arc<TrackState>,
+}
+
+impl<S> Iterator for Tracked<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.inner.next()?;
+        let played = self.state.samples.fetch_add(1, Ordering::Relaxed);
+
+        let fade_start = self.state.fade_start.load(Ordering::Acquire);
+        if fade_start == NO_FADE {
+            return Some(sample);
+        }
+        let fade_len = self.state.fade_len.load(Ordering::Relaxed);
+        let faded = played.saturating_sub(fade_start);
+        if faded >= fade_len {
+            return None;
+        }
+        Some(sample.amplify(1.0 - faded as f32 / fade_len as f32))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for Tracked<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+// ReplayGain のどの値で音量をそろえるか
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    Track,
+    Album,
+}
+
+impl ReplayGainMode {
+    pub fn next(self) -> Self {
+        match self {
+            ReplayGainMode::Off => ReplayGainMode::Track,
+            ReplayGainMode::Track => ReplayGainMode::Album,
+            ReplayGainMode::Album => ReplayGainMode::Off,
+        }
+    }
+
+    // タグから音量補正を決める。タグが無ければ None (再生しながら推定する)
+    fn factor(self, tags: &tags::Tags) -> Option<f32> {
+        let (gain, peak) = match self {
+            ReplayGainMode::Off => return Some(1.0),
+            ReplayGainMode::Track => (tags.track_gain.or(tags.album_gain), tags.track_peak.or(tags.album_peak)),
+            ReplayGainMode::Album => (tags.album_gain.or(tags.track_gain), tags.album_peak.or(tags.track_peak)),
+        };
+        let factor = 10f32.powf(gain? / 20.0);
+        // ピークが分かっていれば、持ち上げても音が割れない範囲に抑える
+        Some(match peak {
+            Some(peak) if peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        })
+    }
+}
+
+// 曲を開くときの設定
+#[derive(Clone, Default)]
+pub struct PlaybackOptions {
+    pub replay_gain: ReplayGainMode,
+    // true なら Sink の速度変更 (音程も変わる) の代わりに、音程を保つ伸縮処理を通す
+    pub preserve_pitch: bool,
+    pub stretch_speed: SharedSpeed,
+    pub eq: SharedEq,
+    pub tap: SampleTap,
+}
+
+// Sink に渡す音源。設定によって処理の組み合わせが変わるので箱に入れて扱う
+pub type TrackSource = Box<dyn Source<Item = f32> + Send>;
+
+// ファイルを先頭から再生するソースとその時計を返す
+pub fn open_track(
+    path: &Path,
+    options: &PlaybackOptions,
+) -> Result<(TrackSource, PlaybackClock)> {
+    let total = Arc::new(OnceLock::new());
+    let (source, clock) = open_at(path, Duration::ZERO, total.clone(), options)?;
+
+    // MP3 などデコーダが長さを返さない形式は、まずタグやフレームヘッダから読み、
+    // それでも分からなければ裏でファイルを最後までデコードして数える
+    if clock.total().is_none()
+        && let Some(length) = tags::read_tags(path).and_then(|t| t.duration)
+    {
+        let _ = total.set(length);
+    }
+    if clock.total().is_none() {
+        let path = path.to_path_buf();
+        thread::spawn(move || {
+            if let Some(length) = scan_duration(&path) {
+                let _ = total.set(length);
+            }
+        });
+    }
+    Ok((source, clock))
+}
+
+// 同じ曲を指定位置から開き直す処理を、裏のスレッドで行う
+// rodio は指定位置までデコードして読み飛ばすので、長い曲の後半へ移るときは UI が止まるほど時間がかかる
+pub struct Reopener {
+    requests: mpsc::Sender<ReopenRequest>,
+    results: mpsc::Receiver<(u64, Result<(TrackSource, PlaybackClock)>)>,
+    // 依頼ごとに増やす番号。取り消した依頼や、後の依頼に追い越された依頼の結果は捨てる
+    generation: u64,
+    // まだ結果が届いていない最新の依頼の位置
+    pending: Option<Duration>,
+}
+
+struct ReopenRequest {
+    generation: u64,
+    path: PathBuf,
+    offset: Duration,
+    total: Arc<OnceLock<Duration>>,
+    options: PlaybackOptions,
+}
+
+impl Default for Reopener {
+    fn default() -> Self {
+        let (requests, receiver) = mpsc::channel::<ReopenRequest>();
+        let (sender, results) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(mut request) = receiver.recv() {
+                // 続けてシークしたときは途中の位置を飛ばし、最後の依頼だけを開く
+                while let Ok(newer) = receiver.try_recv() {
+                    request = newer;
+                }
+                let opened = open_at(&request.path, request.offset, request.total, &request.options);
+                if sender.send((request.generation, opened)).is_err() {
+                    return;
+                }
+            }
+        });
+        Reopener { requests, results, generation: 0, pending: None }
+    }
+}
+
+impl Reopener {
+    // 曲を offset の位置から開き直すよう頼む。曲の長さは元の時計と共有する
+    pub fn request(&mut self, path: &Path, offset: Duration, clock: &PlaybackClock, options: &PlaybackOptions) {
+        self.generation += 1;
+        self.pending = Some(offset);
+        let _ = self.requests.send(ReopenRequest {
+            generation: self.generation,
+            path: path.to_path_buf(),
+            offset,
+            total: clock.total.clone(),
+            options: options.clone(),
+        });
+    }
+
+    // 別の曲に切り替えたときなど、まだ届いていない結果を使わないようにする
+    pub fn cancel(&mut self) {
+        self.generation += 1;
+        self.pending = None;
+    }
+
+    pub fn pending(&self) -> Option<Duration> {
+        self.pending
+    }
+
+    // 最新の依頼の結果が届いていれば返す
+    pub fn poll(&mut self) -> Option<Result<(TrackSource, PlaybackClock)>> {
+        while let Ok((generation, opened)) = self.results.try_recv() {
+            if generation == self.generation && self.pending.take().is_some() {
+                return Some(opened);
+            }
+        }
+        None
+    }
+}
+
+fn open_at(
+    path: &Path,
+    offset: Duration,
+    total: Arc<OnceLock<Duration>>,
+    options: &PlaybackOptions,
+) -> Result<(TrackSource, PlaybackClock)> {
+    let decoder = decode(path)?;
+    if let Some(length) = decoder.total_duration() {
+        let _ = total.set(length);
+    }
+    let sample_rate = decoder.sample_rate();
+    let channels = decoder.channels();
+
+    // 時計は先頭ではなく offset から数え始める
+    let state = Arc::new(TrackState {
+        samples: AtomicU64::new(0),
+        fade_start: AtomicU64::new(NO_FADE),
+        fade_len: AtomicU64::new(0),
+    });
+    let decoded = decoder.skip_duration(offset).convert_samples::<f32>();
+    let tags = tags::read_tags(path).unwrap_or_default();
+    let gained = match options.replay_gain.factor(&tags) {
+        Some(factor) => Gain::fixed(decoded, factor),
+        None => Gain::auto(decoded),
+    };
+    let tracked = Tracked {
+        inner: Equalizer::new(gained, options.eq.clone()),
+        state: state.clone(),
+    };
+    // 時計は伸縮前のサンプルを数えるので、速度を変えても曲中の位置を指す
+    let source: TrackSource = if options.preserve_pitch {
+        Box::new(TimeStretch::new(tracked, options.stretch_speed.clone()))
+    } else {
+        Box::new(tracked)
+    };
+    // ビジュアライザには実際に聞こえる音を渡す
+    let source: TrackSource = Box::new(Tap::new(source, options.tap.clone()));
+    let clock = PlaybackClock {
+        offset,
+        state,
+        sample_rate,
+        channels,
+        total,
+    };
+    Ok((source, clock))
+}
+
+// ファイルを開いてデコーダを作る。MP4 と Opus は rodio の Decoder では開けないので自前のデコーダを使う
+fn decode(path: &Path) -> Result<Box<dyn Source<Item = i16> + Send>> {
+    match format::detect(path) {
+        Some(AudioFormat::Mp4) => Ok(Box::new(Mp4Decoder::new(path)?)),
+        Some(AudioFormat::Opus) => Ok(Box::new(OpusDecoder::new(path)?)),
+        _ => Ok(Box::new(Decoder::new(BufReader::new(File::open(path)?))?)),
+    }
+}
+
+// 再生せずに曲の長さを調べる。デコーダが長さを返さなければ最後までデコードして数える
+pub fn probe_duration(path: &Path) -> Option<Duration> {
+    let decoder = decode(path).ok()?;
+    decoder.total_duration().or_else(|| scan_duration(path))
+}
+
+// ファイル全体をデコードしてサンプル数から長さを求める
+fn scan_duration(path: &Path) -> Option<Duration> {
+    let decoder = decode(path).ok()?;
+    let per_second = decoder.sample_rate() as u64 * decoder.channels() as u64;
+    if per_second == 0 {
+        return None;
+    }
+    let samples = decoder.count() as u64;
+    Some(Duration::from_secs_f64(samples as f64 / per_second as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::output::OutputKind;
+
+    // 1秒の無音の WAV を一時ディレクトリに作る
+    fn silent_wav(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("music_cli_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 8_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..8_000 * 2 {
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    #[test]
+    fn clock_follows_playback_on_the_null_output() {
+        let path = silent_wav("clock.wav");
+        let opened = Instant::now();
+        let output = OutputKind::Null.open().unwrap();
+        let sink = output.new_sink().unwrap();
+        let (source, clock) = open_track(&path, &PlaybackOptions::default()).unwrap();
+        assert_eq!(clock.total(), Some(Duration::from_secs(1)));
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+
+        sink.append(source);
+        thread::sleep(Duration::from_millis(300));
+        // ヌル出力も実時間で音を引き出すので、時計は進むが、出力を開いてからの実時間 (と 10ms の1チャンク、
+        // リサンプラの先読み分) より先には進まない。負荷で遅れることはあるので下限は設けない
+        let elapsed = clock.elapsed();
+        let real = opened.elapsed();
+        assert!(elapsed > Duration::ZERO && elapsed <= real + Duration::from_millis(20), "{:?} > {:?}", elapsed, real);
+
+        sink.sleep_until_end();
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reopener_returns_the_track_at_the_requested_position() {
+        let path = silent_wav("reopen.wav");
+        let options = PlaybackOptions::default();
+        let (_, clock) = open_track(&path, &options).unwrap();
+
+        let mut reopener = Reopener::default();
+        reopener.request(&path, Duration::from_millis(200), &clock, &options);
+        // 後から頼んだ位置だけが使われる
+        reopener.request(&path, Duration::from_millis(700), &clock, &options);
+        assert_eq!(reopener.pending(), Some(Duration::from_millis(700)));
+
+        let (_, reopened) = loop {
+            if let Some(result) = reopener.poll() {
+                break result.unwrap();
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(reopened.elapsed(), Duration::from_millis(700));
+        assert_eq!(reopened.total(), Some(Duration::from_secs(1)));
+        assert_eq!(reopener.pending(), None);
+
+        // 取り消した依頼の結果は捨てる
+        reopener.request(&path, Duration::from_millis(100), &clock, &options);
+        reopener.cancel();
+        thread::sleep(Duration::from_millis(100));
+        assert!(reopener.poll().is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn replay_gain_factor_prefers_the_chosen_value_and_limits_peaks() {
+        let tags = |track_gain, album_gain, track_peak, album_peak| tags::Tags {
+            track_gain,
+            album_gain,
+            track_peak,
+            album_peak,
+            ..tags::Tags::default()
+        };
+        let close = |factor: Option<f32>, expected: f32| factor.is_some_and(|f| (f - expected).abs() < 1e-3);
+
+        let both = tags(Some(-6.0), Some(-12.0), None, None);
+        assert_eq!(ReplayGainMode::Off.factor(&both), Some(1.0));
+        assert!(close(ReplayGainMode::Track.factor(&both), 0.501));
+        assert!(close(ReplayGainMode::Album.factor(&both), 0.251));
+        // 選んだ方が無ければもう一方を使う
+        assert!(close(ReplayGainMode::Track.factor(&tags(None, Some(-6.0), None, None)), 0.501));
+        assert!(close(ReplayGainMode::Album.factor(&tags(Some(-6.0), None, None, None)), 0.501));
+        // +6 dB (2 倍) でも、ピークが 0.8 なら 1.25 倍までしか上げない
+        assert!(close(ReplayGainMode::Track.factor(&tags(Some(6.0), None, Some(0.8), None)), 1.25));
+        assert!(close(ReplayGainMode::Album.factor(&tags(None, Some(6.0), Some(0.8), Some(0.4))), 1.995));
+        // タグが無ければ再生しながら推定する
+        assert_eq!(ReplayGainMode::Track.factor(&tags::Tags::default()), None);
+    }
+}
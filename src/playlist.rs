@@ -0,0 +1,173 @@
+// src/playlist.rs
+
+use std::{
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::tags::TagCache;
+
+// 拡張子が .m3u か .m3u8 のファイル
+pub fn is_playlist(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("m3u") || e.eq_ignore_ascii_case("m3u8"))
+}
+
+// プレイリストに並んでいる曲のパス。相対パスはプレイリストのあるディレクトリから解決する
+// #EXTM3U や #EXTINF などの # で始まる行は曲の行ではないので読み飛ばす。見つからない曲も除く
+pub fn load(path: &Path) -> Result<Vec<PathBuf>> {
+    let bytes = fs::read(path)?;
+    // M3U8 は UTF-8。古い .m3u は Latin-1 のこともあるので、UTF-8 として読めなければそちらで読む
+    let content = match String::from_utf8(bytes) {
+        Ok(content) => content,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    };
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let tracks = content
+        .trim_start_matches('\u{feff}')
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match line.strip_prefix("file://") {
+            Some(uri) => Some(PathBuf::from(percent_decode(uri))),
+            // file 以外の URL は再生できない
+            None if line.contains("://") => None,
+            None => Some(dir.join(line)),
+        })
+        .filter(|track| track.is_file())
+        .collect();
+    Ok(tracks)
+}
+
+// 拡張 M3U の UTF-8 版で保存する。各曲の前に #EXTINF で長さ(秒)と "アーティスト - タイトル" を書く
+// プレイリストと同じディレクトリの下にある曲は、持ち運べるよう相対パスにする
+pub fn save(path: &Path, tracks: &[PathBuf], tags: &TagCache) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut content = String::from("#EXTM3U\n");
+    for track in tracks {
+        let tags = tags.get(track);
+        // 長さが分からない曲は -1 とする決まり
+        let secs = tags.and_then(|t| t.duration).map_or(-1, |d| d.as_secs() as i64);
+        let title = tags.and_then(|t| t.title.clone());
+        let name = match (tags.and_then(|t| t.artist.as_ref()), title) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (None, Some(title)) => title,
+            _ => track.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+        };
+        let location = track.strip_prefix(dir).unwrap_or(track);
+        writeln!(content, "#EXTINF:{},{}", secs, name.replace(['\r', '\n'], " "))?;
+        writeln!(content, "{}", location.display())?;
+    }
+    fs::write(path, content)?;
+    Ok(())
+}
+
+// file:// の URL の "%20" のような部分を元の文字に戻す
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::tags::Tags;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("music_cli_playlist_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn save_then_load_round_trips_with_relative_paths() {
+        let dir = temp_dir("save");
+        let outside = temp_dir("outside").join("far.mp3");
+        let tracks = [dir.join("a b.mp3"), dir.join("sub/untagged.mp3"), outside.clone()];
+        for track in &tracks {
+            fs::write(track, b"").unwrap();
+        }
+        let mut tags = TagCache::default();
+        tags.insert(
+            tracks[0].clone(),
+            Tags {
+                title: Some("Song\nTitle".to_string()),
+                artist: Some("Band".to_string()),
+                duration: Some(Duration::from_secs_f64(215.7)),
+                ..Tags::default()
+            },
+        );
+
+        let playlist = dir.join("list.m3u8");
+        save(&playlist, &tracks, &tags).unwrap();
+        let content = fs::read_to_string(&playlist).unwrap();
+        let expected = format!(
+            "#EXTM3U\n#EXTINF:215,Band - Song Title\na b.mp3\n#EXTINF:-1,untagged\nsub/untagged.mp3\n#EXTINF:-1,far\n{}\n",
+            outside.display()
+        );
+        assert_eq!(content, expected);
+        assert_eq!(load(&playlist).unwrap(), tracks);
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(outside.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn load_handles_bom_latin1_file_urls_and_missing_tracks() {
+        let dir = temp_dir("load");
+        fs::write(dir.join("café.mp3"), b"").unwrap();
+        fs::write(dir.join("sub/with space.mp3"), b"").unwrap();
+
+        // BOM 付きの UTF-8。file:// の URL はデコードし、ほかの URL と見つからない曲は除く
+        let url = format!("file://{}/sub/with%20space.mp3", dir.display());
+        let content = format!(
+            "\u{feff}#EXTM3U\r\n#EXTINF:10,x\r\n  café.mp3  \r\n{}\nhttp://example.com/stream.mp3\nmissing.mp3\n\n",
+            url
+        );
+        let playlist = dir.join("new.m3u8");
+        fs::write(&playlist, content).unwrap();
+        assert_eq!(load(&playlist).unwrap(), [dir.join("café.mp3"), dir.join("sub/with space.mp3")]);
+
+        // UTF-8 として読めない古い .m3u は Latin-1 として読む ("é" は 0xE9)
+        let playlist = dir.join("old.m3u");
+        fs::write(&playlist, b"#EXTM3U\ncaf\xe9.mp3\n").unwrap();
+        assert_eq!(load(&playlist).unwrap(), [dir.join("café.mp3")]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn percent_decode_keeps_invalid_escapes() {
+        assert_eq!(percent_decode("/a%20b/%E3%81%82.mp3"), "/a b/あ.mp3");
+        assert_eq!(percent_decode("100%.mp3"), "100%.mp3");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+
+    #[test]
+    fn is_playlist_checks_the_extension() {
+        assert!(is_playlist(Path::new("a.M3U")));
+        assert!(is_playlist(Path::new("a.m3u8")));
+        assert!(!is_playlist(Path::new("a.mp3")));
+    }
+}
@@ -0,0 +1,489 @@
+// src/queue.rs
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use rand::{seq::SliceRandom, thread_rng, Rng};
+
+// キューの末尾に来たときや曲が終わったときの振る舞い
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    One,
+    All,
+}
+
+impl RepeatMode {
+    pub fn next(self) -> Self {
+        match self {
+            RepeatMode::Off => RepeatMode::All,
+            RepeatMode::All => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Off,
+        }
+    }
+}
+
+// 再生順を保持するキュー
+// ファイルブラウザの表示内容とは独立しているので、別のディレクトリに移動しても次の曲は変わらない
+#[derive(Default)]
+pub struct Queue {
+    tracks: Vec<PathBuf>,
+    // 最後に再生を始めた曲の位置。None なら次は先頭から
+    position: Option<usize>,
+    repeat: RepeatMode,
+    // シャッフル中だけ Some。この周回でまだ再生していない曲の添字を、次に再生する順に末尾から並べる
+    upcoming: Option<Vec<usize>>,
+}
+
+impl Queue {
+    pub fn tracks(&self) -> &[PathBuf] {
+        &self.tracks
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    pub fn position(&self) -> Option<usize> {
+        self.position
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    pub fn cycle_repeat(&mut self) {
+        self.repeat = self.repeat.next();
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    pub fn is_shuffled(&self) -> bool {
+        self.upcoming.is_some()
+    }
+
+    // シャッフルを切り替える。キューの並び自体は変えず、再生順だけを別に持つ
+    pub fn toggle_shuffle(&mut self) {
+        if self.upcoming.is_some() {
+            self.upcoming = None;
+        } else {
+            self.reshuffle();
+        }
+    }
+
+    // 再生中の曲以外をすべて未再生として並べ直し、新しい周回を始める
+    fn reshuffle(&mut self) {
+        let mut order: Vec<usize> = (0..self.tracks.len())
+            .filter(|&i| Some(i) != self.position)
+            .collect();
+        order.shuffle(&mut thread_rng());
+        self.upcoming = Some(order);
+    }
+
+    // キューを丸ごと入れ替え、start 番目の曲を再生中とする
+    pub fn replace(&mut self, tracks: Vec<PathBuf>, start: usize) -> Option<&PathBuf> {
+        self.tracks = tracks;
+        self.position = None;
+        if self.is_shuffled() {
+            self.reshuffle();
+        }
+        self.jump(start)
+    }
+
+    pub fn push(&mut self, path: PathBuf) {
+        self.tracks.push(path);
+        // シャッフル中は未再生の曲のどこかにランダムに混ぜる
+        let index = self.tracks.len() - 1;
+        if let Some(upcoming) = &mut self.upcoming {
+            let at = thread_rng().gen_range(0..=upcoming.len());
+            upcoming.insert(at, index);
+        }
+    }
+
+    pub fn extend(&mut self, paths: impl IntoIterator<Item = PathBuf>) {
+        for path in paths {
+            self.push(path);
+        }
+    }
+
+    // 再生中の曲の直後に割り込ませる。シャッフル中も次に再生される
+    pub fn insert_next(&mut self, path: PathBuf) {
+        let index = self.position.map_or(0, |i| i + 1);
+        self.tracks.insert(index, path);
+        if let Some(upcoming) = &mut self.upcoming {
+            for i in upcoming.iter_mut().filter(|i| **i >= index) {
+                *i += 1;
+            }
+            upcoming.push(index);
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<PathBuf> {
+        if index >= self.tracks.len() {
+            return None;
+        }
+        let removed = self.tracks.remove(index);
+        // 再生位置より前(または再生中の曲自身)を消したら、次に進む先がずれないよう位置を戻す
+        if let Some(pos) = self.position
+            && index <= pos
+        {
+            self.position = pos.checked_sub(1);
+        }
+        if let Some(upcoming) = &mut self.upcoming {
+            upcoming.retain(|&i| i != index);
+            for i in upcoming.iter_mut().filter(|i| **i > index) {
+                *i -= 1;
+            }
+        }
+        Some(removed)
+    }
+
+    // index 番目の曲を上(-1)または下(+1)に動かす。動かした先の位置を返す
+    pub fn move_track(&mut self, index: usize, up: bool) -> Option<usize> {
+        let target = if up { index.checked_sub(1)? } else { index + 1 };
+        if target >= self.tracks.len() {
+            return None;
+        }
+        self.tracks.swap(index, target);
+        let swap = |i: usize| if i == index { target } else if i == target { index } else { i };
+        self.position = self.position.map(swap);
+        if let Some(upcoming) = &mut self.upcoming {
+            for i in upcoming.iter_mut() {
+                *i = swap(*i);
+            }
+        }
+        Some(target)
+    }
+
+    pub fn clear(&mut self) {
+        self.tracks.clear();
+        self.position = None;
+        if let Some(upcoming) = &mut self.upcoming {
+            upcoming.clear();
+        }
+    }
+
+    pub fn jump(&mut self, index: usize) -> Option<&PathBuf> {
+        let track = self.tracks.get(index)?;
+        self.position = Some(index);
+        // 飛んだ先の曲はこの周回で再生済みになる
+        if let Some(upcoming) = &mut self.upcoming {
+            upcoming.retain(|&i| i != index);
+        }
+        Some(track)
+    }
+
+    // 指定した曲を再生位置にする。同じ曲が複数あれば現在位置より前で最も近いものを選ぶ
+    pub fn jump_to_path(&mut self, path: &Path) -> bool {
+        let before = self.position.map_or(0, |i| i + 1).min(self.tracks.len());
+        let found = self.tracks[..before].iter().rposition(|p| p == path)
+            .or_else(|| self.tracks.iter().position(|p| p == path));
+        match found {
+            Some(index) => self.jump(index).is_some(),
+            None => false,
+        }
+    }
+
+    // 次の曲に進む。manual は利用者が明示的に次の曲を選んだ場合で、
+    // そのときはリピート1曲でも次へ進み、末尾では先頭に戻る
+    pub fn advance(&mut self, manual: bool) -> Option<&PathBuf> {
+        if self.tracks.is_empty() {
+            return None;
+        }
+        if self.upcoming.is_some() {
+            return self.advance_shuffled(manual);
+        }
+        let next = match (self.position, self.repeat) {
+            (None, _) => 0,
+            (Some(i), RepeatMode::One) if !manual => i,
+            (Some(i), RepeatMode::Off) if !manual && i + 1 >= self.tracks.len() => {
+                // 最後まで再生し終えたので、次に再生するときは先頭から
+                self.position = None;
+                return None;
+            }
+            (Some(i), _) => (i + 1) % self.tracks.len(),
+        };
+        self.jump(next)
+    }
+
+    // 曲が終わったときに次に再生される曲を、再生位置を動かさずに調べる
+    // シャッフルの周回が終わっていれば、ここで次の周回の順番を決めておく
+    pub fn peek_next(&mut self) -> Option<&PathBuf> {
+        let position = self.position?;
+        if self.repeat == RepeatMode::One {
+            return self.tracks.get(position);
+        }
+        let next = match &self.upcoming {
+            Some(upcoming) => {
+                if upcoming.is_empty() {
+                    if self.repeat == RepeatMode::Off {
+                        return None;
+                    }
+                    self.reshuffle();
+                }
+                *self.upcoming.as_ref()?.last()?
+            }
+            None if position + 1 < self.tracks.len() => position + 1,
+            None if self.repeat == RepeatMode::All => 0,
+            None => return None,
+        };
+        self.tracks.get(next)
+    }
+
+    // シャッフル中は未再生の曲から順に取り出し、すべて再生したら並べ直して次の周回に入る
+    fn advance_shuffled(&mut self, manual: bool) -> Option<&PathBuf> {
+        if self.repeat == RepeatMode::One && !manual && self.position.is_some() {
+            return self.position.and_then(|i| self.tracks.get(i));
+        }
+        if self.upcoming.as_ref().is_some_and(|u| u.is_empty()) {
+            if self.repeat == RepeatMode::Off && !manual {
+                self.position = None;
+                self.reshuffle();
+                return None;
+            }
+            self.reshuffle();
+        }
+        let next = self.upcoming.as_mut()?.pop()?;
+        self.jump(next)
+    }
+}
+
+// ディレクトリ以下を再帰的にたどり、条件に合うファイルをパス順に集める
+pub fn collect_tracks(dir: &Path, is_track: impl Fn(&Path) -> bool + Copy) -> Vec<PathBuf> {
+    let mut visited = HashSet::new();
+    let mut tracks = Vec::new();
+    collect_into(dir, is_track, &mut visited, &mut tracks);
+    tracks
+}
+
+// シンボリックリンクで同じディレクトリに戻ってくることがあるので、実体のパスごとに一度だけたどる
+fn collect_into(
+    dir: &Path,
+    is_track: impl Fn(&Path) -> bool + Copy,
+    visited: &mut HashSet<PathBuf>,
+    tracks: &mut Vec<PathBuf>,
+) {
+    let Ok(real) = fs::canonicalize(dir) else { return };
+    if !visited.insert(real) {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    let mut paths: Vec<PathBuf> = entries.filter_map(Result::ok).map(|e| e.path()).collect();
+    paths.sort();
+
+    for path in paths {
+        if path.is_dir() {
+            collect_into(&path, is_track, visited, tracks);
+        } else if is_track(&path) {
+            tracks.push(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(names: &[&str]) -> Queue {
+        let mut queue = Queue::default();
+        queue.extend(names.iter().map(PathBuf::from));
+        queue
+    }
+
+    fn name(track: Option<&PathBuf>) -> Option<&str> {
+        track.and_then(|p| p.to_str())
+    }
+
+    // 曲が終わるたびに進めたときの再生順 (最大 n 曲)
+    fn play_order(queue: &mut Queue, n: usize) -> Vec<String> {
+        (0..n).map_while(|_| queue.advance(false).map(|p| p.display().to_string())).collect()
+    }
+
+    #[test]
+    fn advance_plays_in_order_and_stops_at_the_end() {
+        let mut queue = queue(&["a", "b", "c"]);
+        assert_eq!(play_order(&mut queue, 5), ["a", "b", "c"]);
+        assert_eq!(queue.position(), None);
+        // 止まったあとは先頭からやり直す
+        assert_eq!(name(queue.advance(false)), Some("a"));
+    }
+
+    #[test]
+    fn manual_advance_wraps_around() {
+        let mut queue = queue(&["a", "b"]);
+        queue.jump(1);
+        assert_eq!(name(queue.advance(true)), Some("a"));
+    }
+
+    #[test]
+    fn insert_next_plays_right_after_the_current_track() {
+        let mut queue = queue(&["a", "b"]);
+        queue.advance(false);
+        queue.insert_next(PathBuf::from("x"));
+        assert_eq!(name(queue.advance(false)), Some("x"));
+        assert_eq!(name(queue.advance(false)), Some("b"));
+    }
+
+    #[test]
+    fn remove_and_move_keep_the_current_track() {
+        let mut queue = queue(&["a", "b", "c", "d"]);
+        queue.jump(2);
+        assert_eq!(queue.remove(0).as_deref(), Some(Path::new("a")));
+        assert_eq!(name(queue.advance(false)), Some("d"));
+
+        queue.jump(0);
+        assert_eq!(queue.move_track(0, false), Some(1));
+        assert_eq!(queue.position(), Some(1));
+        assert_eq!(queue.move_track(0, true), None);
+        assert_eq!(queue.remove(9), None);
+    }
+
+    #[test]
+    fn replace_starts_at_the_given_track() {
+        let mut queue = queue(&["a"]);
+        let tracks = ["x", "y", "z"].map(PathBuf::from).to_vec();
+        assert_eq!(name(queue.replace(tracks, 1)), Some("y"));
+        assert_eq!(name(queue.advance(false)), Some("z"));
+        queue.clear();
+        assert!(queue.is_empty());
+        assert_eq!(queue.advance(false), None);
+    }
+
+    #[test]
+    fn jump_to_path_prefers_the_nearest_earlier_copy() {
+        let mut queue = queue(&["a", "b", "a", "c"]);
+        queue.jump(3);
+        assert!(queue.jump_to_path(Path::new("a")));
+        assert_eq!(queue.position(), Some(2));
+        assert!(!queue.jump_to_path(Path::new("z")));
+    }
+
+    #[test]
+    fn repeat_all_wraps_and_repeat_one_stays() {
+        let mut queue = queue(&["a", "b"]);
+        queue.set_repeat(RepeatMode::All);
+        assert_eq!(play_order(&mut queue, 5), ["a", "b", "a", "b", "a"]);
+
+        queue.set_repeat(RepeatMode::One);
+        assert_eq!(play_order(&mut queue, 3), ["a", "a", "a"]);
+        // 次の曲を選んだときは1曲リピートでも進む
+        assert_eq!(name(queue.advance(true)), Some("b"));
+    }
+
+    #[test]
+    fn repeat_cycles_off_all_one() {
+        let mut queue = Queue::default();
+        queue.cycle_repeat();
+        assert!(queue.repeat() == RepeatMode::All);
+        queue.cycle_repeat();
+        assert!(queue.repeat() == RepeatMode::One);
+        queue.cycle_repeat();
+        assert!(queue.repeat() == RepeatMode::Off);
+    }
+
+    #[test]
+    fn shuffle_plays_every_track_once_per_round() {
+        let mut queue = queue(&["a", "b", "c", "d", "e"]);
+        queue.toggle_shuffle();
+        assert!(queue.is_shuffled());
+        let mut order = play_order(&mut queue, 10);
+        assert_eq!(order.len(), 5);
+        order.sort();
+        assert_eq!(order, ["a", "b", "c", "d", "e"]);
+        // リピートなしなら一周で止まり、次に再生するときは新しい周回から始める
+        assert_eq!(queue.position(), None);
+        assert_eq!(queue.peek_next(), None);
+        assert!(queue.advance(false).is_some());
+    }
+
+    #[test]
+    fn shuffle_with_repeat_all_starts_a_new_round() {
+        let mut queue = queue(&["a", "b", "c"]);
+        queue.set_repeat(RepeatMode::All);
+        queue.toggle_shuffle();
+        let first_round = play_order(&mut queue, 3);
+        let peeked = name(queue.peek_next()).map(str::to_string);
+        let second_round = play_order(&mut queue, 2);
+        // 先読みした曲が、次の周回で実際に最初に流れる
+        assert_eq!(peeked.as_ref(), second_round.first());
+        // 新しい周回は、最後に流れた曲をすぐには繰り返さずに残りの曲から始まる
+        let mut rest: Vec<&String> = first_round[..2].iter().collect();
+        let mut second: Vec<&String> = second_round.iter().collect();
+        rest.sort();
+        second.sort();
+        assert_eq!(second, rest);
+    }
+
+    #[test]
+    fn turning_shuffle_off_continues_in_queue_order() {
+        let mut queue = queue(&["a", "b", "c"]);
+        queue.toggle_shuffle();
+        queue.jump(0);
+        queue.toggle_shuffle();
+        assert!(!queue.is_shuffled());
+        assert_eq!(name(queue.advance(false)), Some("b"));
+    }
+
+    #[test]
+    fn insert_next_plays_right_after_the_current_track_under_shuffle() {
+        let mut queue = queue(&["a", "b", "c"]);
+        queue.toggle_shuffle();
+        queue.advance(false);
+        queue.insert_next(PathBuf::from("x"));
+        assert_eq!(name(queue.advance(false)), Some("x"));
+    }
+
+    #[test]
+    fn peek_next_matches_advance_without_moving() {
+        let mut queue = queue(&["a", "b"]);
+        assert_eq!(queue.peek_next(), None);
+        queue.advance(false);
+        assert_eq!(name(queue.peek_next()), Some("b"));
+        assert_eq!(queue.position(), Some(0));
+
+        queue.jump(1);
+        assert_eq!(queue.peek_next(), None);
+        queue.set_repeat(RepeatMode::All);
+        assert_eq!(name(queue.peek_next()), Some("a"));
+        queue.set_repeat(RepeatMode::One);
+        assert_eq!(name(queue.peek_next()), Some("b"));
+    }
+
+    #[test]
+    fn peek_next_agrees_with_shuffled_advance() {
+        let mut queue = queue(&["a", "b", "c", "d"]);
+        queue.toggle_shuffle();
+        queue.advance(false);
+        for _ in 0..3 {
+            let peeked = queue.peek_next().cloned();
+            assert_eq!(queue.advance(false).cloned(), peeked);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn collect_tracks_visits_each_directory_once_through_symlink_loops() {
+        let root = std::env::temp_dir().join(format!("music_cli_collect_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a.mp3"), b"").unwrap();
+        fs::write(root.join("sub/b.mp3"), b"").unwrap();
+        fs::write(root.join("sub/notes.txt"), b"").unwrap();
+        std::os::unix::fs::symlink(".", root.join("loop")).unwrap();
+        std::os::unix::fs::symlink("..", root.join("sub/up")).unwrap();
+
+        let tracks = collect_tracks(&root, |p| p.extension().is_some_and(|e| e == "mp3"));
+        assert_eq!(tracks, [root.join("a.mp3"), root.join("sub/b.mp3")]);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+// src/search.rs
+
+use crate::browse::Entry;
+
+// フッターの入力欄で何を入力しているか
+#[derive(Clone, Copy, PartialEq)]
+pub enum PromptKind {
+    // 入力に合わせて、一致する項目へ選択を移す
+    Search,
+    // 入力にあいまいに一致する項目だけを一覧に残す
+    Filter,
+    // キューをこの名前のプレイリストとして保存する
+    SavePlaylist,
+}
+
+// 入力中の検索語や絞り込みの文字列、プレイリストの名前
+pub struct Prompt {
+    pub kind: PromptKind,
+    pub text: String,
+    // 入力を始めたときに選択していた項目。Esc で取り消したらここへ戻す
+    pub origin: Option<Entry>,
+}
+
+impl Prompt {
+    pub fn label(&self) -> &'static str {
+        match self.kind {
+            PromptKind::Search => "/",
+            PromptKind::Filter => "Filter: ",
+            PromptKind::SavePlaylist => "Save queue as: ",
+        }
+    }
+}
+
+// 大文字小文字を区別しない部分一致
+pub fn matches(pattern: &str, text: &str) -> bool {
+    text.to_lowercase().contains(&pattern.to_lowercase())
+}
+
+// pattern の文字が順番どおりに text に含まれていれば一致とする (大文字小文字と空白は無視)
+// 例えば "bdy" は "Birthday" に一致する
+pub fn fuzzy_matches(pattern: &str, text: &str) -> bool {
+    let mut chars = text.chars().flat_map(char::to_lowercase);
+    pattern
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .all(|p| chars.any(|c| c == p))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_finds_substrings_ignoring_case() {
+        assert!(matches("love", "All You Need Is LOVE.mp3"));
+        assert!(matches("ÉTÉ", "l'été indien"));
+        assert!(matches("", "anything"));
+        assert!(!matches("lvoe", "love"));
+        // 部分一致では空白も文字のうち
+        assert!(!matches("need is", "Needis"));
+    }
+
+    #[test]
+    fn fuzzy_matches_needs_the_characters_in_order() {
+        assert!(fuzzy_matches("bdy", "Birthday"));
+        assert!(fuzzy_matches("BDY", "birthday"));
+        assert!(fuzzy_matches("", "anything"));
+        assert!(!fuzzy_matches("ydb", "Birthday"));
+        // 同じ文字を2回使うには、text にも2回出てこなければならない
+        assert!(fuzzy_matches("pp", "Happy"));
+        assert!(!fuzzy_matches("ppp", "Happy"));
+        assert!(!fuzzy_matches("birthdays", "Birthday"));
+    }
+
+    #[test]
+    fn fuzzy_matches_skips_whitespace_in_the_pattern() {
+        assert!(fuzzy_matches("  hap  py ", "Happy"));
+        assert!(fuzzy_matches("a b", "Abbey Road"));
+        assert!(fuzzy_matches("abbey road", "AbbeyRoad"));
+        assert!(!fuzzy_matches("road abbey", "Abbey Road"));
+    }
+
+    #[test]
+    fn fuzzy_matches_folds_case_beyond_ascii() {
+        assert!(fuzzy_matches("ÉLN", "élan vital"));
+        assert!(fuzzy_matches("straße", "STRAßE"));
+    }
+}
@@ -0,0 +1,857 @@
+// src/tags.rs
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Cursor, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::format::{self, AudioFormat};
+
+// 音声ファイルのタグ情報
+// MP3 の ID3v1/ID3v2 と FLAC・Ogg の Vorbis コメントから読み取る
+#[derive(Clone, Default)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub track: Option<u32>,
+    pub year: Option<u32>,
+    pub duration: Option<Duration>,
+    // ReplayGain の補正値 (dB) とピーク (1.0 がフルスケール)
+    pub track_gain: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl Tags {
+    // Vorbis コメント形式のキー (ID3 のフレームもこの名前に読み替える) を対応する項目に入れる
+    // 先に見つかった値を優先する
+    fn set(&mut self, key: &str, value: &str) {
+        let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+        if value.is_empty() {
+            return;
+        }
+        match key.to_ascii_uppercase().as_str() {
+            "TITLE" => fill(&mut self.title, value.to_string()),
+            "ARTIST" => fill(&mut self.artist, value.to_string()),
+            "ALBUM" => fill(&mut self.album, value.to_string()),
+            "GENRE" => fill(&mut self.genre, genre_name(value)),
+            // "3/12" のような形式もあるので先頭の数字だけを読む
+            "TRACKNUMBER" => fill_opt(&mut self.track, leading_number(value)),
+            // "2004-05-01" のような日付からは年だけを取り出す
+            "DATE" | "YEAR" => fill_opt(&mut self.year, leading_number(value)),
+            // "-6.50 dB" のように単位が付いていることが多い
+            "REPLAYGAIN_TRACK_GAIN" => fill_opt(&mut self.track_gain, leading_float(value)),
+            "REPLAYGAIN_ALBUM_GAIN" => fill_opt(&mut self.album_gain, leading_float(value)),
+            "REPLAYGAIN_TRACK_PEAK" => fill_opt(&mut self.track_peak, leading_float(value)),
+            "REPLAYGAIN_ALBUM_PEAK" => fill_opt(&mut self.album_peak, leading_float(value)),
+            _ => {}
+        }
+    }
+
+    // "アーティスト – タイトル" の形の表示名。タイトルが無ければ None
+    pub fn display_name(&self) -> Option<String> {
+        let title = self.title.as_ref()?;
+        Some(match &self.artist {
+            Some(artist) => format!("{} – {}", artist, title),
+            None => title.clone(),
+        })
+    }
+}
+
+fn fill(slot: &mut Option<String>, value: String) {
+    if slot.is_none() {
+        *slot = Some(value);
+    }
+}
+
+fn fill_opt<T>(slot: &mut Option<T>, value: Option<T>) {
+    if slot.is_none() {
+        *slot = value;
+    }
+}
+
+// ID3v1 のジャンル番号に対応する名前 (Winamp の拡張分は含めない)
+const ID3V1_GENRES: [&str; 80] = [
+    "Blues", "Classic Rock", "Country", "Dance", "Disco", "Funk", "Grunge", "Hip-Hop",
+    "Jazz", "Metal", "New Age", "Oldies", "Other", "Pop", "R&B", "Rap",
+    "Reggae", "Rock", "Techno", "Industrial", "Alternative", "Ska", "Death Metal", "Pranks",
+    "Soundtrack", "Euro-Techno", "Ambient", "Trip-Hop", "Vocal", "Jazz+Funk", "Fusion", "Trance",
+    "Classical", "Instrumental", "Acid", "House", "Game", "Sound Clip", "Gospel", "Noise",
+    "AlternRock", "Bass", "Soul", "Punk", "Space", "Meditative", "Instrumental Pop", "Instrumental Rock",
+    "Ethnic", "Gothic", "Darkwave", "Techno-Industrial", "Electronic", "Pop-Folk", "Eurodance", "Dream",
+    "Southern Rock", "Comedy", "Cult", "Gangsta", "Top 40", "Christian Rap", "Pop/Funk", "Jungle",
+    "Native American", "Cabaret", "New Wave", "Psychedelic", "Rave", "Showtunes", "Trailer", "Lo-Fi",
+    "Tribal", "Acid Punk", "Acid Jazz", "Polka", "Retro", "Musical", "Rock & Roll", "Hard Rock",
+];
+
+// ID3v2 のジャンルは "(17)" や "17" のように ID3v1 の番号で書かれていることがある
+// "(17)Rock" のように後ろに名前があればそちらを使う
+fn genre_name(value: &str) -> String {
+    let mut number = value;
+    if let Some((inner, rest)) = value.strip_prefix('(').and_then(|v| v.split_once(')')) {
+        if !rest.is_empty() {
+            return rest.to_string();
+        }
+        number = inner;
+    }
+    match number.parse::<usize>().ok().and_then(|n| ID3V1_GENRES.get(n)) {
+        Some(name) => name.to_string(),
+        None => value.to_string(),
+    }
+}
+
+fn leading_number(value: &str) -> Option<u32> {
+    let digits: String = value.trim().chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+fn leading_float(value: &str) -> Option<f32> {
+    let number: String = value.trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || matches!(c, '+' | '-' | '.'))
+        .collect();
+    number.parse().ok()
+}
+
+// 一度読んだタグを覚えておくキャッシュ。描画のたびにファイルを開かないようにする
+#[derive(Default)]
+pub struct TagCache {
+    entries: HashMap<PathBuf, Option<Tags>>,
+}
+
+impl TagCache {
+    // まだ読んでいないファイルのタグを読み込む
+    pub fn load<'a>(&mut self, paths: impl IntoIterator<Item = &'a PathBuf>) {
+        for path in paths {
+            if !self.entries.contains_key(path) {
+                self.entries.insert(path.clone(), read_tags(path));
+            }
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&Tags> {
+        self.entries.get(path).and_then(Option::as_ref)
+    }
+
+    // ライブラリなど、別の場所で読んだタグを登録する
+    pub fn insert(&mut self, path: PathBuf, tags: Tags) {
+        self.entries.insert(path, Some(tags));
+    }
+}
+
+// ファイルの先頭を見て形式を判断し、タグを読む。対応していない形式なら None
+pub fn read_tags(path: &Path) -> Option<Tags> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let mut tags = Tags::default();
+
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).ok()?;
+    reader.seek(SeekFrom::Start(0)).ok()?;
+
+    // FLAC の前に ID3v2 が付いていることもあるので、先に読み飛ばしておく
+    let audio_start = if &magic[..3] == b"ID3" {
+        read_id3v2(&mut reader, &mut tags).ok()?
+    } else {
+        0
+    };
+    reader.seek(SeekFrom::Start(audio_start)).ok()?;
+    reader.read_exact(&mut magic).ok()?;
+
+    if &magic == b"fLaC" {
+        read_flac(&mut reader, &mut tags).ok()?;
+    } else if &magic == b"OggS" {
+        reader.seek(SeekFrom::Start(audio_start)).ok()?;
+        read_ogg(&mut reader, &mut tags).ok()?;
+    } else if format::detect(path) == Some(AudioFormat::Mp3) {
+        read_id3v1(&mut reader, &mut tags);
+        tags.duration = mp3_duration(&mut reader, audio_start);
+    } else if audio_start == 0 {
+        return None;
+    }
+    Some(tags)
+}
+
+// --- ID3v2 ---
+
+// ID3v2 タグを読み、タグの直後(音声データの始まり)の位置を返す
+fn read_id3v2<R: Read + Seek>(reader: &mut R, tags: &mut Tags) -> std::io::Result<u64> {
+    let mut header = [0u8; 10];
+    reader.read_exact(&mut header)?;
+    let version = header[3];
+    let flags = header[5];
+    let size = synchsafe(&header[6..10]) as u64;
+    let tag_end = 10 + size + if flags & 0x10 != 0 { 10 } else { 0 };
+
+    if !(2..=4).contains(&version) {
+        return Ok(tag_end);
+    }
+
+    // タグ全体に非同期化がかかっている場合はメモリ上で戻してから読む
+    // 途中で壊れていても、それまでに読めた項目は使う
+    if flags & 0x80 != 0 && version < 4 {
+        let mut body = vec![0u8; size as usize];
+        reader.read_exact(&mut body)?;
+        let body = remove_unsync(&body);
+        let len = body.len() as u64;
+        let _ = read_id3v2_frames(&mut Cursor::new(body), version, flags, len, tags);
+    } else {
+        let _ = read_id3v2_frames(reader, version, flags, size, tags);
+    }
+    Ok(tag_end)
+}
+
+fn read_id3v2_frames<R: Read + Seek>(
+    reader: &mut R,
+    version: u8,
+    flags: u8,
+    size: u64,
+    tags: &mut Tags,
+) -> std::io::Result<()> {
+    let start = reader.stream_position()?;
+    let end = start + size;
+
+    // 拡張ヘッダは中身を使わないので飛ばす
+    if flags & 0x40 != 0 && version >= 3 {
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf)?;
+        let skip = if version == 4 {
+            (synchsafe(&buf) as i64) - 4
+        } else {
+            u32::from_be_bytes(buf) as i64
+        };
+        reader.seek(SeekFrom::Current(skip.max(0)))?;
+    }
+
+    let (id_len, header_len) = if version == 2 { (3, 6) } else { (4, 10) };
+    while reader.stream_position()? + header_len < end {
+        let mut header = [0u8; 10];
+        reader.read_exact(&mut header[..header_len as usize])?;
+        // パディングに入ったら終わり
+        if header[0] == 0 {
+            break;
+        }
+        let id = String::from_utf8_lossy(&header[..id_len]).into_owned();
+        let frame_size = match version {
+            2 => u32::from_be_bytes([0, header[3], header[4], header[5]]),
+            3 => u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+            _ => synchsafe(&header[4..8]),
+        } as u64;
+        let frame_flags = if version == 2 { 0 } else { u16::from_be_bytes([header[8], header[9]]) };
+
+        // 画像などの大きなフレームやテキスト以外は読まずに飛ばす
+        let key = frame_key(&id);
+        let compressed_or_encrypted = match version {
+            3 => frame_flags & 0x00c0 != 0,
+            4 => frame_flags & 0x000c != 0,
+            _ => false,
+        };
+        if key.is_none() || compressed_or_encrypted || frame_size > 0x10000 {
+            reader.seek(SeekFrom::Current(frame_size as i64))?;
+            continue;
+        }
+
+        let mut data = vec![0u8; frame_size as usize];
+        reader.read_exact(&mut data)?;
+        if version == 4 {
+            if frame_flags & 0x0001 != 0 && data.len() >= 4 {
+                data.drain(..4);
+            }
+            if frame_flags & 0x0002 != 0 {
+                data = remove_unsync(&data);
+            }
+        }
+
+        match key {
+            // TXXX は "説明\0値" の形で、説明をキーとして扱う
+            Some("TXXX") => {
+                let text = decode_text(&data);
+                let mut parts = text.splitn(2, '\0');
+                if let (Some(desc), Some(value)) = (parts.next(), parts.next()) {
+                    tags.set(desc, value);
+                }
+            }
+            Some(key) => {
+                // ID3v2.4 では複数の値が \0 区切りで入るので最初の値だけを使う
+                let text = decode_text(&data);
+                let value = text.split('\0').next().unwrap_or_default();
+                tags.set(key, value);
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+// ID3v2 のフレーム ID を Vorbis コメントのキーに読み替える
+fn frame_key(id: &str) -> Option<&'static str> {
+    Some(match id {
+        "TIT2" | "TT2" => "TITLE",
+        "TPE1" | "TP1" => "ARTIST",
+        "TALB" | "TAL" => "ALBUM",
+        "TCON" | "TCO" => "GENRE",
+        "TRCK" | "TRK" => "TRACKNUMBER",
+        "TYER" | "TDRC" | "TYE" => "DATE",
+        "TXXX" | "TXX" => "TXXX",
+        _ => return None,
+    })
+}
+
+// 先頭1バイトの文字コード指定に従ってテキストフレームを文字列にする
+fn decode_text(data: &[u8]) -> String {
+    let Some((&encoding, body)) = data.split_first() else { return String::new() };
+    match encoding {
+        1 => decode_utf16(body, None),
+        2 => decode_utf16(body, Some(false)),
+        3 => String::from_utf8_lossy(body).into_owned(),
+        _ => latin1(body),
+    }
+}
+
+// little_endian が None なら BOM を見て判断する。\0 区切りの値ごとに BOM が付いていることもある
+fn decode_utf16(body: &[u8], little_endian: Option<bool>) -> String {
+    let mut le = little_endian.unwrap_or(false);
+    let mut units = Vec::with_capacity(body.len() / 2);
+    for pair in body.chunks_exact(2) {
+        match (pair[0], pair[1]) {
+            (0xff, 0xfe) if little_endian.is_none() => le = true,
+            (0xfe, 0xff) if little_endian.is_none() => le = false,
+            (a, b) => units.push(if le { u16::from_le_bytes([a, b]) } else { u16::from_be_bytes([a, b]) }),
+        }
+    }
+    String::from_utf16_lossy(&units)
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+fn synchsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, &b| (acc << 7) | (b & 0x7f) as u32)
+}
+
+// 非同期化で挿入された 0xFF 直後の 0x00 を取り除く
+fn remove_unsync(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut prev = 0u8;
+    for &b in data {
+        if !(prev == 0xff && b == 0x00) {
+            out.push(b);
+        }
+        prev = b;
+    }
+    out
+}
+
+// --- ID3v1 ---
+
+// ファイル末尾128バイトの ID3v1 タグ。ID3v2 で見つからなかった項目だけを埋める
+fn read_id3v1<R: Read + Seek>(reader: &mut R, tags: &mut Tags) {
+    let mut tag = [0u8; 128];
+    if reader.seek(SeekFrom::End(-128)).is_err() || reader.read_exact(&mut tag).is_err() {
+        return;
+    }
+    if &tag[..3] != b"TAG" {
+        return;
+    }
+    tags.set("TITLE", &latin1(&tag[3..33]));
+    tags.set("ARTIST", &latin1(&tag[33..63]));
+    tags.set("ALBUM", &latin1(&tag[63..93]));
+    tags.set("DATE", &latin1(&tag[93..97]));
+    // 255 は「指定なし」
+    if let Some(genre) = ID3V1_GENRES.get(tag[127] as usize) {
+        tags.set("GENRE", genre);
+    }
+    // ID3v1.1 ではコメント欄の最後の2バイトが 0 とトラック番号になる
+    if tag[125] == 0 && tag[126] != 0 {
+        fill_opt(&mut tags.track, Some(tag[126] as u32));
+    }
+}
+
+// --- MP3 の長さ ---
+
+// 最初のフレームの Xing/Info/VBRI ヘッダからフレーム数を読み、無ければ固定ビットレートとして見積もる
+fn mp3_duration<R: Read + Seek>(reader: &mut R, audio_start: u64) -> Option<Duration> {
+    let file_len = reader.seek(SeekFrom::End(0)).ok()?;
+    reader.seek(SeekFrom::Start(audio_start)).ok()?;
+    let mut buf = vec![0u8; 16 * 1024];
+    let read = reader.read(&mut buf).ok()?;
+    let buf = &buf[..read];
+
+    let offset = (0..buf.len().saturating_sub(4)).find(|&i| {
+        buf[i] == 0xff && buf[i + 1] & 0xe0 == 0xe0 && parse_mp3_header(&buf[i..i + 4]).is_some()
+    })?;
+    let frame = parse_mp3_header(&buf[offset..offset + 4])?;
+
+    let xing_at = offset + frame.side_info_len + 4;
+    if let Some(tag) = buf.get(xing_at..xing_at + 12)
+        && (&tag[..4] == b"Xing" || &tag[..4] == b"Info")
+        && u32::from_be_bytes([tag[4], tag[5], tag[6], tag[7]]) & 1 != 0
+    {
+        let frames = u32::from_be_bytes([tag[8], tag[9], tag[10], tag[11]]);
+        return Some(frame.frames_to_duration(frames));
+    }
+    let vbri_at = offset + 36;
+    if let Some(tag) = buf.get(vbri_at..vbri_at + 18)
+        && &tag[..4] == b"VBRI"
+    {
+        let frames = u32::from_be_bytes([tag[14], tag[15], tag[16], tag[17]]);
+        return Some(frame.frames_to_duration(frames));
+    }
+
+    let audio_len = file_len.saturating_sub(audio_start + offset as u64);
+    Some(Duration::from_secs_f64(audio_len as f64 * 8.0 / (frame.bitrate_kbps as f64 * 1000.0)))
+}
+
+struct Mp3Frame {
+    bitrate_kbps: u32,
+    sample_rate: u32,
+    samples_per_frame: u32,
+    side_info_len: usize,
+}
+
+impl Mp3Frame {
+    fn frames_to_duration(&self, frames: u32) -> Duration {
+        Duration::from_secs_f64(frames as f64 * self.samples_per_frame as f64 / self.sample_rate as f64)
+    }
+}
+
+// MPEG Audio Layer III のフレームヘッダだけを読む
+fn parse_mp3_header(h: &[u8]) -> Option<Mp3Frame> {
+    const BITRATES_V1: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+    const BITRATES_V2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+    let version = (h[1] >> 3) & 0b11; // 0: MPEG2.5, 2: MPEG2, 3: MPEG1
+    let layer = (h[1] >> 1) & 0b11; // 1: Layer III
+    if version == 1 || layer != 1 {
+        return None;
+    }
+    let bitrate_index = (h[2] >> 4) as usize;
+    let rate_index = ((h[2] >> 2) & 0b11) as usize;
+    if bitrate_index == 0 || bitrate_index == 15 || rate_index == 3 {
+        return None;
+    }
+    let mono = (h[3] >> 6) == 0b11;
+    let mpeg1 = version == 3;
+
+    let base_rate = [44100, 48000, 32000][rate_index];
+    let sample_rate = match version {
+        3 => base_rate,
+        2 => base_rate / 2,
+        _ => base_rate / 4,
+    };
+    Some(Mp3Frame {
+        bitrate_kbps: if mpeg1 { BITRATES_V1[bitrate_index] } else { BITRATES_V2[bitrate_index] },
+        sample_rate,
+        samples_per_frame: if mpeg1 { 1152 } else { 576 },
+        side_info_len: match (mpeg1, mono) {
+            (true, false) => 32,
+            (true, true) | (false, false) => 17,
+            (false, true) => 9,
+        },
+    })
+}
+
+// --- FLAC ---
+
+// "fLaC" の直後から始まるメタデータブロックを読む
+fn read_flac<R: Read + Seek>(reader: &mut R, tags: &mut Tags) -> std::io::Result<()> {
+    loop {
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header)?;
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+        match block_type {
+            // STREAMINFO: サンプルレートと総サンプル数から長さを求める
+            0 => {
+                let mut info = vec![0u8; len];
+                reader.read_exact(&mut info)?;
+                if info.len() >= 18 {
+                    let sample_rate = ((info[10] as u32) << 12) | ((info[11] as u32) << 4) | (info[12] as u32 >> 4);
+                    let total_samples = (((info[13] & 0x0f) as u64) << 32)
+                        | u32::from_be_bytes([info[14], info[15], info[16], info[17]]) as u64;
+                    if sample_rate > 0 && total_samples > 0 {
+                        tags.duration = Some(Duration::from_secs_f64(total_samples as f64 / sample_rate as f64));
+                    }
+                }
+            }
+            4 => {
+                let mut block = vec![0u8; len];
+                reader.read_exact(&mut block)?;
+                read_vorbis_comments(&block, tags);
+            }
+            _ => {
+                reader.seek(SeekFrom::Current(len as i64))?;
+            }
+        }
+        if is_last {
+            return Ok(());
+        }
+    }
+}
+
+// Vorbis コメント: ベンダー文字列のあとに "KEY=value" が並ぶ (長さはすべてリトルエンディアン)
+fn read_vorbis_comments(block: &[u8], tags: &mut Tags) {
+    let mut pos = 0;
+    let next_u32 = |pos: &mut usize| -> Option<usize> {
+        let bytes = block.get(*pos..*pos + 4)?;
+        *pos += 4;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    };
+
+    let Some(vendor_len) = next_u32(&mut pos) else { return };
+    pos += vendor_len;
+    let Some(count) = next_u32(&mut pos) else { return };
+    for _ in 0..count {
+        let Some(len) = next_u32(&mut pos) else { return };
+        let Some(comment) = block.get(pos..pos + len) else { return };
+        pos += len;
+        let comment = String::from_utf8_lossy(comment);
+        if let Some((key, value)) = comment.split_once('=') {
+            tags.set(key, value);
+        }
+    }
+}
+
+// --- Ogg ---
+
+// Ogg Vorbis / Opus の最初の2つのパケット (識別ヘッダとコメントヘッダ) を読む
+// 長さは最後のページのグラニュール位置 (そこまでのサンプル数) から求める
+fn read_ogg<R: Read + Seek>(reader: &mut R, tags: &mut Tags) -> std::io::Result<()> {
+    // コメントにはカバー画像が入っていることもあるが、これより大きいものは壊れているとみなす
+    const MAX_PACKET: usize = 16 * 1024 * 1024;
+
+    let mut packets: Vec<Vec<u8>> = Vec::new();
+    let mut packet = Vec::new();
+    let mut serial = None;
+    while packets.len() < 2 {
+        let mut header = [0u8; 27];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != b"OggS" {
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+        let page_serial = u32::from_le_bytes([header[14], header[15], header[16], header[17]]);
+        let mut lacing = vec![0u8; header[26] as usize];
+        reader.read_exact(&mut lacing)?;
+        let mut body = vec![0u8; lacing.iter().map(|&l| l as usize).sum()];
+        reader.read_exact(&mut body)?;
+        // 多重化された別のストリームのページは読み飛ばす
+        if *serial.get_or_insert(page_serial) != page_serial {
+            continue;
+        }
+        let mut pos = 0;
+        for &len in &lacing {
+            packet.extend_from_slice(&body[pos..pos + len as usize]);
+            pos += len as usize;
+            // 255 未満の区切りでパケットが終わる。255 なら次の区切り (次のページのこともある) に続く
+            if len < 255 {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+        if packet.len() > MAX_PACKET {
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+    }
+
+    let (ident, comment) = (&packets[0], &packets[1]);
+    // Opus は常に 48kHz で数え、先頭の pre-skip 分は再生されない
+    let (sample_rate, pre_skip) = if ident.starts_with(b"\x01vorbis") && ident.len() >= 16 {
+        (u32::from_le_bytes([ident[12], ident[13], ident[14], ident[15]]), 0)
+    } else if ident.starts_with(b"OpusHead") && ident.len() >= 12 {
+        (48_000, u16::from_le_bytes([ident[10], ident[11]]) as u64)
+    } else {
+        return Ok(());
+    };
+    if let Some(block) = comment.strip_prefix(b"\x03vorbis").or_else(|| comment.strip_prefix(b"OpusTags")) {
+        read_vorbis_comments(block, tags);
+    }
+    if let (Some(serial), true) = (serial, sample_rate > 0)
+        && let Some(granule) = ogg_last_granule(reader, serial)?
+    {
+        tags.duration = Some(Duration::from_secs_f64(granule.saturating_sub(pre_skip) as f64 / sample_rate as f64));
+    }
+    Ok(())
+}
+
+// ファイル末尾から探した、指定したストリームの最後のページのグラニュール位置
+pub fn ogg_last_granule<R: Read + Seek>(reader: &mut R, serial: u32) -> std::io::Result<Option<u64>> {
+    // ページは最大でも 64KiB 程度なので、末尾のその範囲に最後のページの頭がある
+    const TAIL: u64 = 65_536 + 27 + 255;
+
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(len.saturating_sub(TAIL)))?;
+    let mut tail = Vec::new();
+    reader.read_to_end(&mut tail)?;
+    if tail.len() < 27 {
+        return Ok(None);
+    }
+    let granule = (0..=tail.len() - 27).rev().find_map(|i| {
+        let page = &tail[i..i + 27];
+        let granule = u64::from_le_bytes(page[6..14].try_into().ok()?);
+        // -1 はそのページでパケットが終わっていないという印
+        let matches = &page[..4] == b"OggS"
+            && u32::from_le_bytes([page[14], page[15], page[16], page[17]]) == serial
+            && granule != u64::MAX;
+        matches.then_some(granule)
+    });
+    Ok(granule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1つのページに packets を入れる (CRC は読まないので 0 のまま)
+    fn ogg_page(serial: u32, granule: u64, packets: &[&[u8]]) -> Vec<u8> {
+        let mut lacing = Vec::new();
+        let mut body = Vec::new();
+        for packet in packets {
+            lacing.extend(std::iter::repeat_n(255u8, packet.len() / 255));
+            lacing.push((packet.len() % 255) as u8);
+            body.extend_from_slice(packet);
+        }
+        let mut page = b"OggS\0\0".to_vec();
+        page.extend(granule.to_le_bytes());
+        page.extend(serial.to_le_bytes());
+        page.extend([0; 8]);
+        page.push(lacing.len() as u8);
+        page.extend(lacing);
+        page.extend(body);
+        page
+    }
+
+    fn comment_packet(prefix: &[u8], comments: &[&str]) -> Vec<u8> {
+        let mut packet = prefix.to_vec();
+        packet.extend(4u32.to_le_bytes());
+        packet.extend(b"test");
+        packet.extend((comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            packet.extend((comment.len() as u32).to_le_bytes());
+            packet.extend(comment.as_bytes());
+        }
+        packet
+    }
+
+    #[test]
+    fn reads_vorbis_comments_and_length_from_ogg_vorbis() {
+        let mut ident = b"\x01vorbis\0\0\0\0\x02".to_vec();
+        ident.extend(44_100u32.to_le_bytes());
+        ident.extend([0; 14]);
+        // 255 バイトを超えるコメントは区切りをまたぐ
+        let long = format!("COMMENT={}", "x".repeat(600));
+        let comment = comment_packet(b"\x03vorbis", &["TITLE=Song", "ARTIST=Band", "TRACKNUMBER=3", &long]);
+        let mut file = ogg_page(7, 0, &[&ident]);
+        file.extend(ogg_page(7, 0, &[&comment]));
+        // 別のストリームのページは無視する
+        file.extend(ogg_page(9, 999_999_999, &[b"other"]));
+        file.extend(ogg_page(7, 44_100 * 90, &[b"audio"]));
+
+        let mut tags = Tags::default();
+        read_ogg(&mut Cursor::new(file), &mut tags).unwrap();
+        assert_eq!(tags.title.as_deref(), Some("Song"));
+        assert_eq!(tags.artist.as_deref(), Some("Band"));
+        assert_eq!(tags.track, Some(3));
+        assert_eq!(tags.duration, Some(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn reads_opus_tags_and_subtracts_pre_skip() {
+        let mut ident = b"OpusHead\x01\x02".to_vec();
+        ident.extend(312u16.to_le_bytes());
+        ident.extend(48_000u32.to_le_bytes());
+        ident.extend([0; 3]);
+        let comment = comment_packet(b"OpusTags", &["TITLE=Opus Song"]);
+        let mut file = ogg_page(1, 0, &[&ident]);
+        file.extend(ogg_page(1, 0, &[&comment]));
+        file.extend(ogg_page(1, 48_000 * 61 + 312, &[b"audio"]));
+
+        let mut tags = Tags::default();
+        read_ogg(&mut Cursor::new(file), &mut tags).unwrap();
+        assert_eq!(tags.title.as_deref(), Some("Opus Song"));
+        assert_eq!(tags.duration, Some(Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn rejects_a_stream_that_is_not_ogg() {
+        let mut tags = Tags::default();
+        assert!(read_ogg(&mut Cursor::new(b"RIFF\0\0\0\0WAVEfmt ".repeat(4)), &mut tags).is_err());
+    }
+
+    // ID3v2 のフレーム。v2.2 は3文字の ID と3バイトの長さ、v2.4 は長さが synchsafe
+    fn id3_frame(version: u8, id: &str, flags: u16, data: &[u8]) -> Vec<u8> {
+        let mut frame = id.as_bytes().to_vec();
+        let len = data.len() as u32;
+        match version {
+            2 => frame.extend(&len.to_be_bytes()[1..]),
+            3 => frame.extend(len.to_be_bytes()),
+            _ => frame.extend([(len >> 21) as u8 & 0x7f, (len >> 14) as u8 & 0x7f, (len >> 7) as u8 & 0x7f, len as u8 & 0x7f]),
+        }
+        if version > 2 {
+            frame.extend(flags.to_be_bytes());
+        }
+        frame.extend(data);
+        frame
+    }
+
+    // ID3v2 タグ全体。後ろに 10 バイトのパディングを付ける
+    fn id3_tag(version: u8, flags: u8, body: &[u8]) -> Vec<u8> {
+        let size = body.len() as u32 + 10;
+        let mut tag = vec![b'I', b'D', b'3', version, 0, flags];
+        tag.extend([(size >> 21) as u8 & 0x7f, (size >> 14) as u8 & 0x7f, (size >> 7) as u8 & 0x7f, size as u8 & 0x7f]);
+        tag.extend(body);
+        tag.extend([0; 10]);
+        tag
+    }
+
+    fn utf16(bom_le: bool, text: &str) -> Vec<u8> {
+        let mut bytes = if bom_le { vec![0xff, 0xfe] } else { vec![0xfe, 0xff] };
+        for unit in text.encode_utf16() {
+            bytes.extend(if bom_le { unit.to_le_bytes() } else { unit.to_be_bytes() });
+        }
+        bytes
+    }
+
+    #[test]
+    fn reads_utf16_text_frames_from_id3v2_3() {
+        let mut title = vec![1];
+        title.extend(utf16(true, "Café"));
+        title.extend([0, 0]);
+        let mut artist = vec![1];
+        artist.extend(utf16(false, "Ünder"));
+        let mut body = id3_frame(3, "TIT2", 0, &title);
+        body.extend(id3_frame(3, "TPE1", 0, &artist));
+        // 読まないフレームは飛ばす
+        body.extend(id3_frame(3, "APIC", 0, &[0; 40]));
+        body.extend(id3_frame(3, "TRCK", 0, b"\x003/12"));
+        let tag = id3_tag(3, 0, &body);
+
+        let mut tags = Tags::default();
+        let end = read_id3v2(&mut Cursor::new(&tag), &mut tags).unwrap();
+        assert_eq!(end, tag.len() as u64);
+        assert_eq!(tags.title.as_deref(), Some("Café"));
+        assert_eq!(tags.artist.as_deref(), Some("Ünder"));
+        assert_eq!(tags.track, Some(3));
+    }
+
+    #[test]
+    fn removes_unsynchronisation_from_id3v2_3_tags() {
+        // Latin-1 の "ÿ" (0xFF) の後ろには、非同期化で 0x00 が挟まる
+        let body = id3_frame(3, "TALB", 0, b"\x00A\xffB");
+        let mut unsynced = Vec::new();
+        for &b in &body {
+            unsynced.push(b);
+            if b == 0xff {
+                unsynced.push(0);
+            }
+        }
+        let mut tags = Tags::default();
+        read_id3v2(&mut Cursor::new(id3_tag(3, 0x80, &unsynced)), &mut tags).unwrap();
+        assert_eq!(tags.album.as_deref(), Some("AÿB"));
+    }
+
+    #[test]
+    fn reads_replaygain_from_id3v2_4_txxx_frames() {
+        let mut body = id3_frame(4, "TXXX", 0, b"\x03REPLAYGAIN_TRACK_GAIN\0-6.50 dB");
+        body.extend(id3_frame(4, "TXXX", 0, b"\x03replaygain_album_peak\x000.988"));
+        // v2.4 では複数の値が \0 で区切られる。最初の値を使う
+        body.extend(id3_frame(4, "TPE1", 0, b"\x03First\0Second"));
+        body.extend(id3_frame(4, "TDRC", 0, b"\x032004-05-01"));
+        let mut tags = Tags::default();
+        read_id3v2(&mut Cursor::new(id3_tag(4, 0, &body)), &mut tags).unwrap();
+        assert_eq!(tags.track_gain, Some(-6.5));
+        assert_eq!(tags.album_peak, Some(0.988));
+        assert_eq!(tags.artist.as_deref(), Some("First"));
+        assert_eq!(tags.year, Some(2004));
+    }
+
+    #[test]
+    fn reads_id3v2_2_three_letter_frames() {
+        let mut body = id3_frame(2, "TT2", 0, b"\x00Old Song");
+        body.extend(id3_frame(2, "TP1", 0, b"\x00Old Band"));
+        let mut tags = Tags::default();
+        read_id3v2(&mut Cursor::new(id3_tag(2, 0, &body)), &mut tags).unwrap();
+        assert_eq!(tags.title.as_deref(), Some("Old Song"));
+        assert_eq!(tags.artist.as_deref(), Some("Old Band"));
+    }
+
+    #[test]
+    fn id3v1_1_fills_only_the_missing_fields() {
+        let mut tag = vec![0u8; 128];
+        tag[..3].copy_from_slice(b"TAG");
+        tag[3..8].copy_from_slice(b"Title");
+        tag[33..39].copy_from_slice(b"Artist");
+        tag[93..97].copy_from_slice(b"1999");
+        tag[126] = 7;
+        tag[127] = 17;
+        let mut file = vec![0xaa; 300];
+        file.extend(tag);
+
+        let mut tags = Tags { title: Some("From ID3v2".to_string()), ..Tags::default() };
+        read_id3v1(&mut Cursor::new(file), &mut tags);
+        assert_eq!(tags.title.as_deref(), Some("From ID3v2"));
+        assert_eq!(tags.artist.as_deref(), Some("Artist"));
+        assert_eq!(tags.year, Some(1999));
+        assert_eq!(tags.genre.as_deref(), Some("Rock"));
+        assert_eq!(tags.track, Some(7));
+    }
+
+    #[test]
+    fn reads_flac_streaminfo_and_vorbis_comments() {
+        // 44.1kHz、ステレオ、16 ビット、441000 サンプル (10 秒)
+        let mut info = vec![0u8; 34];
+        let rate = 44_100u32;
+        let samples = 441_000u64;
+        info[10] = (rate >> 12) as u8;
+        info[11] = (rate >> 4) as u8;
+        info[12] = ((rate & 0x0f) << 4) as u8 | (1 << 1);
+        info[13] = (15 << 4) | (samples >> 32) as u8;
+        info[14..18].copy_from_slice(&(samples as u32).to_be_bytes());
+        let comment = comment_packet(b"", &["TITLE=Flac Song", "DATE=2010"]);
+
+        let mut file = vec![0, 0, 0, 34];
+        file.extend(info);
+        // 使わないブロック (PADDING) は飛ばす
+        file.extend([1, 0, 0, 8]);
+        file.extend([0; 8]);
+        file.extend([0x84, 0, 0, comment.len() as u8]);
+        file.extend(comment);
+
+        let mut tags = Tags::default();
+        read_flac(&mut Cursor::new(file), &mut tags).unwrap();
+        assert_eq!(tags.duration, Some(Duration::from_secs(10)));
+        assert_eq!(tags.title.as_deref(), Some("Flac Song"));
+        assert_eq!(tags.year, Some(2010));
+    }
+
+    // MPEG1 Layer III、128kbps、44.1kHz、ステレオのフレームヘッダ
+    const MP3_HEADER: [u8; 4] = [0xff, 0xfb, 0x90, 0x00];
+
+    #[test]
+    fn mp3_length_comes_from_the_xing_header() {
+        let mut file = vec![0u8; 20];
+        file.extend(MP3_HEADER);
+        // ステレオの MPEG1 ではサイド情報 32 バイトのあとに Xing ヘッダがある
+        file.extend([0; 32]);
+        file.extend(b"Xing");
+        file.extend(1u32.to_be_bytes());
+        file.extend(1_000u32.to_be_bytes());
+        file.extend([0; 400]);
+        let duration = mp3_duration(&mut Cursor::new(file), 20).unwrap();
+        assert_eq!(duration, Duration::from_secs_f64(1_000.0 * 1152.0 / 44_100.0));
+    }
+
+    #[test]
+    fn mp3_without_a_vbr_header_is_estimated_from_the_bitrate() {
+        // 128kbps で 16000 バイトなら 1 秒
+        let mut file = MP3_HEADER.to_vec();
+        file.resize(16_000, 0);
+        assert_eq!(mp3_duration(&mut Cursor::new(file), 0), Some(Duration::from_secs(1)));
+    }
+}
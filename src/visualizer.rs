@@ -0,0 +1,277 @@
+// src/visualizer.rs
+
+use std::{
+    collections::VecDeque,
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use rodio::Source;
+
+// 画面に渡すために残しておくサンプル数 (モノラルに混ぜた後)。FFT の長さでもある
+pub const FFT_SIZE: usize = 2048;
+// 音声スレッドでこの数だけ貯まったらまとめて共有バッファに移す
+const FLUSH_LEN: usize = 512;
+
+// スペクトラムの表示範囲 (Hz) と、棒の高さに割り当てる音量の範囲 (dB)
+const MIN_FREQ: f32 = 40.0;
+const MAX_FREQ: f32 = 16_000.0;
+const FLOOR_DB: f32 = -70.0;
+// 棒が下がるときは1フレームでこの割合だけ前の高さを残し、ちらつきを抑える
+const FALLOFF: f32 = 0.7;
+
+struct TapBuffer {
+    samples: VecDeque<f32>,
+    sample_rate: u32,
+}
+
+// Sink に流れるサンプルの写しを画面側に渡す共有バッファ
+// クロスフェード中は2曲が同時に流れるので、最後に流れ始めた曲のサンプルだけを残す
+#[derive(Clone)]
+pub struct SampleTap {
+    buffer: Arc<Mutex<TapBuffer>>,
+    generation: Arc<AtomicU64>,
+}
+
+impl Default for SampleTap {
+    fn default() -> Self {
+        SampleTap {
+            buffer: Arc::new(Mutex::new(TapBuffer {
+                samples: VecDeque::with_capacity(FFT_SIZE),
+                sample_rate: 44_100,
+            })),
+            generation: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl SampleTap {
+    // 再生を止めたときに表示を消す
+    pub fn clear(&self) {
+        if let Ok(mut buffer) = self.buffer.lock() {
+            buffer.samples.clear();
+        }
+    }
+
+    // 直近のサンプルとサンプルレートを返す
+    fn snapshot(&self) -> (Vec<f32>, u32) {
+        match self.buffer.lock() {
+            Ok(buffer) => (buffer.samples.iter().copied().collect(), buffer.sample_rate),
+            Err(_) => (Vec::new(), 0),
+        }
+    }
+}
+
+// 通過するサンプルをモノラルに混ぜて SampleTap に書き写すラッパー
+pub struct Tap<S> {
+    inner: S,
+    tap: SampleTap,
+    // 最初のサンプルが引き出されたときに振られる番号 (0 ならまだ)
+    // 先読みした曲は前の曲が終わるまで引き出されないので、開いた時点では番号を取らない
+    generation: u64,
+    pending: Vec<f32>,
+    // 今のフレームで足し合わせている途中の値と、足したチャンネル数
+    frame_sum: f32,
+    frame_channels: u16,
+}
+
+impl<S: Source<Item = f32>> Tap<S> {
+    pub fn new(inner: S, tap: SampleTap) -> Self {
+        Tap {
+            inner,
+            tap,
+            generation: 0,
+            pending: Vec::with_capacity(FLUSH_LEN),
+            frame_sum: 0.0,
+            frame_channels: 0,
+        }
+    }
+
+    fn flush(&mut self) {
+        // 音声スレッドを待たせないよう、画面側が使っていたら次の機会に回す
+        let Ok(mut buffer) = self.tap.buffer.try_lock() else {
+            if self.pending.len() > FFT_SIZE {
+                self.pending.drain(..self.pending.len() - FFT_SIZE);
+            }
+            return;
+        };
+        if self.tap.generation.load(Ordering::Relaxed) == self.generation {
+            buffer.sample_rate = self.inner.sample_rate();
+            buffer.samples.extend(self.pending.drain(..));
+            let excess = buffer.samples.len().saturating_sub(FFT_SIZE);
+            buffer.samples.drain(..excess);
+        }
+        self.pending.clear();
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Tap<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.inner.next()?;
+        if self.generation == 0 {
+            self.generation = self.tap.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        }
+        self.frame_sum += sample;
+        self.frame_channels += 1;
+        if self.frame_channels >= self.inner.channels().max(1) {
+            self.pending.push(self.frame_sum / self.frame_channels as f32);
+            self.frame_sum = 0.0;
+            self.frame_channels = 0;
+            if self.pending.len() >= FLUSH_LEN {
+                self.flush();
+            }
+        }
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Tap<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+// ビジュアライザの表示方法
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum VisualizerMode {
+    #[default]
+    Spectrum,
+    Scope,
+    Off,
+}
+
+impl VisualizerMode {
+    pub fn next(self) -> Self {
+        match self {
+            VisualizerMode::Spectrum => VisualizerMode::Scope,
+            VisualizerMode::Scope => VisualizerMode::Off,
+            VisualizerMode::Off => VisualizerMode::Spectrum,
+        }
+    }
+}
+
+pub struct Visualizer {
+    pub mode: VisualizerMode,
+    pub tap: SampleTap,
+    // 前のフレームの棒の高さ (0.0〜1.0)
+    bars: Vec<f32>,
+}
+
+impl Visualizer {
+    pub fn new(tap: SampleTap) -> Self {
+        Visualizer {
+            mode: VisualizerMode::default(),
+            tap,
+            bars: Vec::new(),
+        }
+    }
+
+    // 直近のサンプル (-1.0〜1.0) をそのまま返す
+    pub fn waveform(&self) -> Vec<f32> {
+        self.tap.snapshot().0
+    }
+
+    // 低い周波数から順に count 本の棒の高さ (0.0〜1.0) を返す。帯域は対数で等分する
+    pub fn spectrum(&mut self, count: usize) -> &[f32] {
+        let (samples, sample_rate) = self.tap.snapshot();
+        let mut target = vec![0.0; count];
+        if samples.len() == FFT_SIZE && sample_rate > 0 {
+            let magnitudes = magnitude_spectrum(&samples);
+            let bin_width = sample_rate as f32 / FFT_SIZE as f32;
+            let max_freq = MAX_FREQ.min(sample_rate as f32 / 2.0);
+            let ratio = max_freq / MIN_FREQ;
+            for (i, value) in target.iter_mut().enumerate() {
+                let low = MIN_FREQ * ratio.powf(i as f32 / count as f32);
+                let high = MIN_FREQ * ratio.powf((i + 1) as f32 / count as f32);
+                // 低音側は1本の棒が1つのビンより狭くなるので、少なくとも1つは含める
+                let first = ((low / bin_width) as usize).min(magnitudes.len() - 1);
+                let last = ((high / bin_width) as usize).clamp(first + 1, magnitudes.len());
+                let peak = magnitudes[first..last].iter().copied().fold(0.0, f32::max);
+                let db = 20.0 * peak.max(1e-9).log10();
+                *value = ((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0);
+            }
+        }
+
+        // 上がるときはすぐに、下がるときはゆっくり追いかける
+        self.bars.resize(count, 0.0);
+        for (bar, target) in self.bars.iter_mut().zip(target) {
+            *bar = if target >= *bar { target } else { *bar * FALLOFF + target * (1.0 - FALLOFF) };
+        }
+        &self.bars
+    }
+}
+
+// ハン窓をかけて FFT し、各ビンの振幅を返す (正弦波の振幅 1.0 がおおむね 1.0 になるよう正規化)
+fn magnitude_spectrum(samples: &[f32]) -> Vec<f32> {
+    let n = samples.len();
+    let mut re: Vec<f32> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, s)| s * 0.5 * (1.0 - (2.0 * PI * i as f32 / n as f32).cos()))
+        .collect();
+    let mut im = vec![0.0; n];
+    fft(&mut re, &mut im);
+    // 窓で半分になった分と、正負の周波数に分かれた分を戻す
+    let scale = 4.0 / n as f32;
+    (0..n / 2).map(|i| (re[i] * re[i] + im[i] * im[i]).sqrt() * scale).collect()
+}
+
+// 基数2の時間間引き FFT (長さは2の累乗)
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    // ビット反転の順に並べ替える
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let tr = re[b] * cos - im[b] * sin;
+                let ti = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+}